@@ -0,0 +1,211 @@
+//! Zenotta addresses are the 32 byte SHA3-256 hash of a public key. In a script
+//! an address literal is written as `@` followed by the 64 hex characters of the
+//! address and an 8 hex character checksum, which is the first 4 bytes of the
+//! SHA3-256 hash of the address bytes. The checksum catches mistyped addresses
+//! before any funds can be sent to them.
+
+use std::fmt;
+use sha3::{Digest, Sha3_256};
+
+/// Length of an address in bytes
+pub const ADDRESS_LENGTH: usize = 32;
+
+/// Length of an address checksum in bytes
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// A validated Zenotta address
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address([u8; ADDRESS_LENGTH]);
+
+/// Reasons an address literal can fail validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    WrongLength(usize),
+    NonHexCharacter(char),
+    ChecksumMismatch
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl Address {
+    /// Creates an address from its raw bytes
+    ///
+    /// ### Arguments
+    ///
+    /// * `bytes`   - The 32 bytes of the address
+    pub fn from_bytes(bytes: [u8; ADDRESS_LENGTH]) -> Address {
+        Address(bytes)
+    }
+
+    /// Parses and validates the hex form of an address, including its checksum.
+    /// Hex digits may be upper or lower case.
+    ///
+    /// ### Arguments
+    ///
+    /// * `literal` - The address and checksum as hex, without the leading `@`
+    pub fn from_hex(literal: &str) -> Result<Address, AddressError> {
+        let expected_length = (ADDRESS_LENGTH + CHECKSUM_LENGTH) * 2;
+        let char_count = literal.chars().count();
+
+        if char_count != expected_length {
+            return Err(AddressError::WrongLength(char_count));
+        }
+
+        if let Some(c) = literal.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(AddressError::NonHexCharacter(c));
+        }
+
+        // Every character is an ASCII hex digit, so each pair is a single byte
+        let bytes = (0..literal.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&literal[i..i + 2], 16).unwrap())
+            .collect::<Vec<u8>>();
+
+        let mut address = [0; ADDRESS_LENGTH];
+        address.copy_from_slice(&bytes[..ADDRESS_LENGTH]);
+
+        if bytes[ADDRESS_LENGTH..] != Address::checksum(&address) {
+            return Err(AddressError::ChecksumMismatch);
+        }
+
+        Ok(Address::from_bytes(address))
+    }
+
+    /// Derives a new address from a seed address and a nonce, as the SHA3-256
+    /// hash of the seed bytes followed by the nonce in big endian. The same
+    /// seed and nonce always give the same address.
+    ///
+    /// ### Arguments
+    ///
+    /// * `seed`    - The address the new address is derived from
+    /// * `nonce`   - Number distinguishing addresses derived from the same seed
+    pub fn derive(seed: &Address, nonce: u64) -> Address {
+        let mut hasher = Sha3_256::new();
+        hasher.update(seed.0);
+        hasher.update(nonce.to_be_bytes());
+
+        let mut address = [0; ADDRESS_LENGTH];
+        address.copy_from_slice(&hasher.finalize());
+
+        Address::from_bytes(address)
+    }
+
+    /// Gets the raw bytes of the address
+    pub fn as_bytes(&self) -> &[u8; ADDRESS_LENGTH] {
+        &self.0
+    }
+
+    /// Gets the hex form of the address followed by its checksum, as it
+    /// would be written in a script after the `@`
+    pub fn to_hex(&self) -> String {
+        self.0
+            .iter()
+            .chain(Address::checksum(&self.0).iter())
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Calculates the checksum for the given address bytes
+    ///
+    /// ### Arguments
+    ///
+    /// * `bytes`   - The bytes of the address
+    fn checksum(bytes: &[u8; ADDRESS_LENGTH]) -> [u8; CHECKSUM_LENGTH] {
+        let hash = Sha3_256::digest(bytes);
+        let mut checksum = [0; CHECKSUM_LENGTH];
+        checksum.copy_from_slice(&hash[..CHECKSUM_LENGTH]);
+
+        checksum
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "@{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Address({})", self)
+    }
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::WrongLength(length) => {
+                write!(f, "expected {} hex characters, but found {}", (ADDRESS_LENGTH + CHECKSUM_LENGTH) * 2, length)
+            }
+            AddressError::NonHexCharacter(c) => {
+                write!(f, "{:?} is not a hex character", c)
+            }
+            AddressError::ChecksumMismatch => {
+                write!(f, "the checksum does not match the address")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, AddressError, ADDRESS_LENGTH};
+
+    fn address() -> Address {
+        Address::from_bytes([0xab; ADDRESS_LENGTH])
+    }
+
+    #[test]
+    fn hex_round_trips_through_from_hex() {
+        let hex = address().to_hex();
+
+        assert_eq!(hex.len(), 72);
+        assert_eq!(Address::from_hex(&hex), Ok(address()));
+        assert_eq!(Address::from_hex(&hex.to_uppercase()), Ok(address()));
+
+        for nonce in 0..8 {
+            let derived = Address::derive(&address(), nonce);
+            assert_eq!(Address::from_hex(&derived.to_hex()), Ok(derived));
+        }
+    }
+
+    #[test]
+    fn addresses_must_have_a_matching_checksum() {
+        let mut hex = address().to_hex();
+        let last = if hex.ends_with('0') { "1" } else { "0" };
+        hex.replace_range(71.., last);
+
+        assert_eq!(Address::from_hex(&hex), Err(AddressError::ChecksumMismatch));
+
+        // Changing the address rather than the checksum is caught too
+        let mut hex = address().to_hex();
+        hex.replace_range(..2, "ac");
+
+        assert_eq!(Address::from_hex(&hex), Err(AddressError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn addresses_must_have_the_right_length() {
+        let hex = address().to_hex();
+
+        assert_eq!(Address::from_hex(&hex[..70]), Err(AddressError::WrongLength(70)));
+        assert_eq!(Address::from_hex(&format!("{}00", hex)), Err(AddressError::WrongLength(74)));
+        assert_eq!(Address::from_hex(&hex[..64]), Err(AddressError::WrongLength(64)));
+        assert_eq!(Address::from_hex(""), Err(AddressError::WrongLength(0)));
+    }
+
+    #[test]
+    fn addresses_must_only_contain_hex_characters() {
+        let mut hex = address().to_hex();
+        hex.replace_range(10..11, "g");
+
+        assert_eq!(Address::from_hex(&hex), Err(AddressError::NonHexCharacter('g')));
+
+        // Length is counted in characters, so a multibyte character is reported as itself
+        let mut hex = address().to_hex();
+        hex.replace_range(10..11, "é");
+
+        assert_eq!(Address::from_hex(&hex), Err(AddressError::NonHexCharacter('é')));
+    }
+}
@@ -0,0 +1,756 @@
+//! A canonical byte encoding of execution results and ledger state. Two values
+//! encode to the same bytes exactly when they hold the same data, on any
+//! platform and in any build, so their encodings or hashes can be compared
+//! between nodes. Numbers are written as fixed width big endian integers,
+//! text and sequences are prefixed with their length, and each variant of an
+//! enum is prefixed with a tag byte of its own.
+
+use std::collections::{BTreeMap, BTreeSet};
+use sha3::{Digest, Sha3_256};
+use crate::address::Address;
+use crate::compiler::{AssignmentValue, Trigger, Variable};
+use crate::decimal::{Decimal, RoundingMode};
+use crate::error::{LexError, ParseError, RuntimeError, SemanticError, ZqlError};
+use crate::grammar::OpAtom;
+use crate::ledger::{DataRecord, LedgerEntry, UnspentOutput};
+use crate::lexer::Span;
+use crate::syntax::{Block, Expr, Payment, Predicate, Query, QueryField, StackStmt, Stmt};
+use crate::transaction::{Asset, DataEncoding, DataOperation, TransactionIntent, TransactionOperation, Transfer};
+
+/// Values that have a canonical byte encoding
+pub trait Canonical {
+    /// Appends the canonical encoding of the value
+    ///
+    /// ### Arguments
+    ///
+    /// * `out` - The bytes to append the encoding to
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// Gets the canonical encoding of a value
+///
+/// ### Arguments
+///
+/// * `value`   - The value to encode
+pub fn canonical_bytes<T: Canonical + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+/// Gets the SHA3-256 hash of the canonical encoding of a value
+///
+/// ### Arguments
+///
+/// * `value`   - The value to hash
+pub fn canonical_hash<T: Canonical + ?Sized>(value: &T) -> [u8; 32] {
+    Sha3_256::digest(&canonical_bytes(value)).into()
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl Canonical for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Canonical for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Canonical for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+}
+
+impl Canonical for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode(out);
+    }
+}
+
+impl Canonical for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Canonical for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl<T: Canonical> Canonical for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Canonical> Canonical for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out);
+    }
+}
+
+impl<T: Canonical + ?Sized> Canonical for &T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl<T: Canonical + ?Sized> Canonical for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl<T: Canonical> Canonical for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode(out),
+            Some(value) => {
+                1u8.encode(out);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl<A: Canonical, B: Canonical> Canonical for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<K: Canonical, V: Canonical> Canonical for BTreeMap<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+
+        for (key, value) in self {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl<T: Canonical> Canonical for BTreeSet<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl Canonical for Decimal {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.units().to_be_bytes());
+    }
+}
+
+impl Canonical for RoundingMode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RoundingMode::Down => 0u8.encode(out),
+            RoundingMode::Up => 1u8.encode(out),
+            RoundingMode::HalfUp => 2u8.encode(out),
+            RoundingMode::HalfEven => 3u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for Address {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Canonical for Span {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.start.encode(out);
+        self.end.encode(out);
+        self.line.encode(out);
+        self.column.encode(out);
+    }
+}
+
+impl Canonical for Asset {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Asset::Znt => 0u8.encode(out),
+            Asset::Sdl => 1u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for DataEncoding {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            DataEncoding::Json => 0u8.encode(out),
+            DataEncoding::Utf8 => 1u8.encode(out),
+            DataEncoding::Hex => 2u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for Transfer {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.payee.encode(out);
+        self.amount.encode(out);
+        self.asset.encode(out);
+    }
+}
+
+impl Canonical for DataOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            DataOperation::Create { key, content, encoding } => {
+                0u8.encode(out);
+                key.encode(out);
+                content.encode(out);
+                encoding.encode(out);
+            }
+            DataOperation::Update { key, content, encoding, owners } => {
+                1u8.encode(out);
+                key.encode(out);
+                content.encode(out);
+                encoding.encode(out);
+                owners.encode(out);
+            }
+            DataOperation::Delete { key, owners } => {
+                2u8.encode(out);
+                key.encode(out);
+                owners.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for TransactionOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            TransactionOperation::Transfer(transfer) => {
+                0u8.encode(out);
+                transfer.encode(out);
+            }
+            TransactionOperation::Mint(mint) => {
+                1u8.encode(out);
+                mint.encode(out);
+            }
+            TransactionOperation::Data(operation) => {
+                2u8.encode(out);
+                operation.encode(out);
+            }
+            TransactionOperation::CreateAddress(address) => {
+                3u8.encode(out);
+                address.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for TransactionIntent {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.span.encode(out);
+        self.payer.encode(out);
+        self.operations.encode(out);
+    }
+}
+
+impl Canonical for LedgerEntry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.sequence.encode(out);
+        self.payer.encode(out);
+        self.payee.encode(out);
+        self.amount.encode(out);
+        self.asset.encode(out);
+    }
+}
+
+impl Canonical for UnspentOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.owner.encode(out);
+        self.amount.encode(out);
+        self.asset.encode(out);
+    }
+}
+
+impl Canonical for DataRecord {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.key.encode(out);
+        self.owner.encode(out);
+        self.content.encode(out);
+        self.encoding.encode(out);
+    }
+}
+
+impl Canonical for AssignmentValue {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            AssignmentValue::Text(text) => {
+                0u8.encode(out);
+                text.encode(out);
+            }
+            AssignmentValue::Number(number) => {
+                1u8.encode(out);
+                number.encode(out);
+            }
+            AssignmentValue::Bool(value) => {
+                2u8.encode(out);
+                value.encode(out);
+            }
+            AssignmentValue::Address(address) => {
+                3u8.encode(out);
+                address.encode(out);
+            }
+            AssignmentValue::List(items) => {
+                4u8.encode(out);
+                items.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for OpAtom {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            OpAtom::To => 0u8.encode(out),
+            OpAtom::Multiply => 1u8.encode(out),
+            OpAtom::Add => 2u8.encode(out),
+            OpAtom::Subtract => 3u8.encode(out),
+            OpAtom::Divide => 4u8.encode(out),
+            OpAtom::LessThan => 5u8.encode(out),
+            OpAtom::GreaterThan => 6u8.encode(out),
+            OpAtom::LessThanOrEqualTo => 7u8.encode(out),
+            OpAtom::GreaterThanOrEqualTo => 8u8.encode(out),
+            OpAtom::EqualTo => 9u8.encode(out),
+            OpAtom::NotEqualTo => 10u8.encode(out),
+            OpAtom::And => 11u8.encode(out),
+            OpAtom::Or => 12u8.encode(out),
+            OpAtom::Not => 13u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for Expr {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Expr::Number { value, span } => {
+                0u8.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Expr::Bool { value, span } => {
+                1u8.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Expr::Text { value, span } => {
+                2u8.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Expr::Address { value, span } => {
+                3u8.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Expr::Variable { name, span } => {
+                4u8.encode(out);
+                name.encode(out);
+                span.encode(out);
+            }
+            Expr::List { items, span } => {
+                5u8.encode(out);
+                items.encode(out);
+                span.encode(out);
+            }
+            Expr::Unary { op, operand, span } => {
+                6u8.encode(out);
+                op.encode(out);
+                operand.encode(out);
+                span.encode(out);
+            }
+            Expr::Binary { op, left, right, span } => {
+                7u8.encode(out);
+                op.encode(out);
+                left.encode(out);
+                right.encode(out);
+                span.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for QueryField {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            QueryField::Amount => 0u8.encode(out),
+            QueryField::Transact => 1u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for Predicate {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Predicate::WhoIs { address, span } => {
+                0u8.encode(out);
+                address.encode(out);
+                span.encode(out);
+            }
+            Predicate::WhoIn { addresses, span } => {
+                1u8.encode(out);
+                addresses.encode(out);
+                span.encode(out);
+            }
+            Predicate::Amount { op, value, span } => {
+                2u8.encode(out);
+                op.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for Query {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.field.encode(out);
+        self.asset.encode(out);
+        self.predicates.encode(out);
+        self.span.encode(out);
+    }
+}
+
+impl Canonical for Payment {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.payee.encode(out);
+        self.amount.encode(out);
+        self.asset.encode(out);
+        self.span.encode(out);
+    }
+}
+
+impl Canonical for StackStmt {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            StackStmt::Pay { payments, span } => {
+                0u8.encode(out);
+                payments.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Get { target, query, span } => {
+                1u8.encode(out);
+                target.encode(out);
+                query.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Create { key, content, encoding, span } => {
+                2u8.encode(out);
+                key.encode(out);
+                content.encode(out);
+                encoding.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Update { key, content, encoding, predicates, span } => {
+                3u8.encode(out);
+                key.encode(out);
+                content.encode(out);
+                encoding.encode(out);
+                predicates.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Delete { key, predicates, span } => {
+                4u8.encode(out);
+                key.encode(out);
+                predicates.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Mint { payee, amount, asset, span } => {
+                5u8.encode(out);
+                payee.encode(out);
+                amount.encode(out);
+                asset.encode(out);
+                span.encode(out);
+            }
+            StackStmt::NewAddress { target, span } => {
+                6u8.encode(out);
+                target.encode(out);
+                span.encode(out);
+            }
+            StackStmt::Transact { statements, span } => {
+                7u8.encode(out);
+                statements.encode(out);
+                span.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for Stmt {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Stmt::Set { name, value, span } => {
+                0u8.encode(out);
+                name.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Stmt::Let { name, value, span } => {
+                1u8.encode(out);
+                name.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Stmt::Const { name, value, span } => {
+                2u8.encode(out);
+                name.encode(out);
+                value.encode(out);
+                span.encode(out);
+            }
+            Stmt::If { condition, then_block, else_block, span } => {
+                3u8.encode(out);
+                condition.encode(out);
+                then_block.encode(out);
+                else_block.encode(out);
+                span.encode(out);
+            }
+            Stmt::While { condition, block, span } => {
+                4u8.encode(out);
+                condition.encode(out);
+                block.encode(out);
+                span.encode(out);
+            }
+            Stmt::For { variable, list, block, span } => {
+                5u8.encode(out);
+                variable.encode(out);
+                list.encode(out);
+                block.encode(out);
+                span.encode(out);
+            }
+            Stmt::When { condition, block, span } => {
+                6u8.encode(out);
+                condition.encode(out);
+                block.encode(out);
+                span.encode(out);
+            }
+            Stmt::Stack { statement, span } => {
+                7u8.encode(out);
+                statement.encode(out);
+                span.encode(out);
+            }
+        }
+    }
+}
+
+impl Canonical for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.statements.encode(out);
+        self.span.encode(out);
+    }
+}
+
+impl Canonical for Variable {
+    /// A variable bound to a query is encoded with the query, as the variable
+    /// is refreshed with its result whenever triggers are evaluated
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.value.encode(out);
+        self.constant.encode(out);
+        self.query.encode(out);
+    }
+}
+
+impl Canonical for Trigger {
+    /// A trigger is encoded with its condition and block, which decide what
+    /// it does once it fires, along with the scopes it captured
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.condition.encode(out);
+        self.block.encode(out);
+        self.locals.encode(out);
+        self.span.encode(out);
+    }
+}
+
+impl Canonical for LexError {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            LexError::UnexpectedCharacter { .. } => 0u8.encode(out),
+            LexError::InvalidNumber { .. } => 1u8.encode(out),
+            LexError::UnknownOperator { .. } => 2u8.encode(out),
+            LexError::UnterminatedString { .. } => 3u8.encode(out),
+            LexError::InvalidEscape { .. } => 4u8.encode(out),
+            LexError::InvalidAddress { .. } => 5u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for ParseError {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ParseError::UnexpectedToken { .. } => 0u8.encode(out),
+            ParseError::UnexpectedEnd { .. } => 1u8.encode(out),
+            ParseError::Expected { .. } => 2u8.encode(out),
+            ParseError::StackKeywordInHeap { .. } => 3u8.encode(out),
+            ParseError::HeapKeywordInStack { .. } => 4u8.encode(out),
+            ParseError::NestingTooDeep { .. } => 5u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for SemanticError {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SemanticError::UnsupportedExpression { .. } => 0u8.encode(out),
+            SemanticError::UndeclaredVariable { .. } => 1u8.encode(out),
+            SemanticError::ConstantReassignment { .. } => 2u8.encode(out),
+            SemanticError::AlreadyDeclared { .. } => 3u8.encode(out),
+            SemanticError::InvalidStackStatement { .. } => 4u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for RuntimeError {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RuntimeError::UndefinedVariable { .. } => 0u8.encode(out),
+            RuntimeError::TypeMismatch { .. } => 1u8.encode(out),
+            RuntimeError::InvalidOperator { .. } => 2u8.encode(out),
+            RuntimeError::ConstantReassignment { .. } => 3u8.encode(out),
+            RuntimeError::AlreadyDeclared { .. } => 4u8.encode(out),
+            RuntimeError::DivisionByZero { .. } => 5u8.encode(out),
+            RuntimeError::MissingPayer { .. } => 6u8.encode(out),
+            RuntimeError::Overflow { .. } => 7u8.encode(out),
+            RuntimeError::Underflow { .. } => 8u8.encode(out),
+            RuntimeError::OutOfFuel { .. } => 9u8.encode(out),
+            RuntimeError::NestingTooDeep { .. } => 10u8.encode(out),
+            RuntimeError::InvalidAmount { .. } => 11u8.encode(out),
+            RuntimeError::InvalidEncoding { .. } => 12u8.encode(out),
+            RuntimeError::MintNotPermitted { .. } => 13u8.encode(out),
+            RuntimeError::LedgerRejected { .. } => 14u8.encode(out)
+        }
+    }
+}
+
+impl Canonical for ZqlError {
+    /// Errors are encoded with tags for their kind and with their location,
+    /// which together identify the error. Their messages are left out, so
+    /// rewording a message doesn't change the encoding.
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ZqlError::Lex(error) => {
+                0u8.encode(out);
+                error.encode(out);
+            }
+            ZqlError::Parse(error) => {
+                1u8.encode(out);
+                error.encode(out);
+            }
+            ZqlError::Semantic(error) => {
+                2u8.encode(out);
+                error.encode(out);
+            }
+            ZqlError::Runtime(error) => {
+                3u8.encode(out);
+                error.encode(out);
+            }
+        }
+
+        self.span().encode(out);
+    }
+}
+
+impl<T: Canonical> Canonical for Result<T, ZqlError> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Ok(value) => {
+                0u8.encode(out);
+                value.encode(out);
+            }
+            Err(error) => {
+                1u8.encode(out);
+                error.encode(out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::{Address, ADDRESS_LENGTH};
+    use crate::compiler::AssignmentValue;
+    use crate::decimal::Decimal;
+    use crate::error::{RuntimeError, ZqlError};
+    use crate::lexer::Span;
+    use crate::transaction::{Asset, TransactionOperation, Transfer};
+    use super::canonical_bytes;
+
+    #[test]
+    fn numbers_are_fixed_width_big_endian() {
+        assert_eq!(canonical_bytes(&258u64), vec![0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(canonical_bytes(&Decimal::from_int(-1)), (-1_000_000_000_000_000_000i128).to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn text_and_lists_are_length_prefixed() {
+        let list = AssignmentValue::List(vec![
+            AssignmentValue::Text(String::from("ab")),
+            AssignmentValue::Bool(true)
+        ]);
+
+        assert_eq!(canonical_bytes(&list), vec![
+            4, 0, 0, 0, 0, 0, 0, 0, 2,
+            0, 0, 0, 0, 0, 0, 0, 0, 2, b'a', b'b',
+            2, 1
+        ]);
+    }
+
+    #[test]
+    fn operations_are_tagged() {
+        let transfer = Transfer {
+            payee: Address::from_bytes([7; ADDRESS_LENGTH]),
+            amount: "0.000000000000000001".parse().unwrap(),
+            asset: Asset::Sdl
+        };
+
+        let mut expected = vec![1];
+        expected.extend([7; ADDRESS_LENGTH]);
+        expected.extend(1i128.to_be_bytes());
+        expected.push(1);
+
+        assert_eq!(canonical_bytes(&TransactionOperation::Mint(transfer)), expected);
+    }
+
+    #[test]
+    fn errors_are_encoded_by_kind_and_location() {
+        let mismatch = |found: &str| ZqlError::Runtime(RuntimeError::TypeMismatch {
+            expected: String::from("a number"),
+            found: String::from(found),
+            span: Span::default()
+        });
+
+        let mut expected = vec![3, 1];
+        expected.extend(canonical_bytes(&Span::default()));
+
+        assert_eq!(canonical_bytes(&mismatch("Bool(true)")), expected);
+        assert_eq!(canonical_bytes(&mismatch("Text(\"a\")")), expected);
+        assert_ne!(
+            canonical_bytes(&ZqlError::Runtime(RuntimeError::DivisionByZero { span: Span::default() })),
+            expected
+        );
+    }
+}
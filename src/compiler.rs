@@ -1,20 +1,82 @@
-use std::collections::HashMap;
-use crate::grammar::{GrammarAtom, StackKeyword, HeapKeyword, OpAtom};
-use crate::syntax::ParseNode;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::mem;
+use crate::address::Address;
+use crate::decimal::{Decimal, RoundingMode};
+use crate::error::{RuntimeError, ZqlError};
+use crate::fuel::{get_fuel_cost, Operation, DEFAULT_FUEL_LIMIT};
+use crate::grammar::{OpAtom, MAX_NESTING_DEPTH};
+use crate::lexer::Span;
+use crate::ledger::{Ledger, LedgerEntry};
+use crate::syntax::{Block, Expr, Payment, Predicate, Query, QueryField, StackStmt, Stmt};
+use crate::transaction::{Asset, DataEncoding, DataOperation, TransactionIntent, TransactionOperation, Transfer};
 
 
 /// Assignment value union workaround (unions are unsafe and full of crap)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AssignmentValue {
     Text(String),
-    Number(f64),
-    Address(String)
+    Number(Decimal),
+    Bool(bool),
+    Address(Address),
+    List(Vec<AssignmentValue>)
+}
+
+/// A variable held in a scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub value: AssignmentValue,
+    /// Whether the variable was declared with CONST, or provided by the host,
+    /// so can't be assigned to
+    pub constant: bool,
+    /// The query a GET assigned the variable from, if that was its latest
+    /// assignment. The query is run again to refresh the variable before
+    /// triggers are evaluated.
+    pub query: Option<Query>
+}
+
+/// The variables declared at a single level of nesting, ordered by name so
+/// that iterating over them gives the same order on every node
+pub type Scope = BTreeMap<String, Variable>;
+
+/// A chain of nested scopes. The first scope holds the globals of the script and
+/// is never dropped, while every block that runs pushes a scope of its own which
+/// is dropped again once the block ends.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    scopes: Vec<Scope>
+}
+
+/// A block of a script waiting for its condition to hold, registered by WHEN.
+/// Globals are read as they are when the trigger is evaluated, while the
+/// variables of any enclosing blocks are captured as they were at registration.
+/// Variables assigned by GET, captured or not, hold the result of their query
+/// against the ledger at the time of evaluation.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub condition: Expr,
+    pub block: Block,
+    pub locals: Vec<Scope>,
+    pub span: Span
 }
 
 /// A generic model for execution of the script
 #[derive(Debug, Clone)]
 pub struct ExecutionModel {
-    pub assignments: HashMap<String, AssignmentValue>
+    pub environment: Environment,
+    pub payer: Option<Address>,
+    pub rounding: RoundingMode,
+    /// Fuel an execution may use before it is halted
+    pub fuel_limit: u64,
+    /// Fuel used by the current execution so far
+    pub fuel_used: u64,
+    /// Number of operators and lists currently being evaluated inside one another
+    pub expression_depth: usize,
+    pub block_height: u64,
+    pub triggers: Vec<Trigger>,
+    pub transactions: Vec<TransactionIntent>,
+    /// Assets the host permits the script to mint with NEW
+    pub mintable_assets: Vec<Asset>
 }
 
 
@@ -26,11 +88,227 @@ pub struct Compiler(pub ExecutionModel);
 
 /*------ IMPLEMENTATIONS ------*/
 
+impl Variable {
+    /// Creates a variable holding a value that isn't bound to a query
+    ///
+    /// ### Arguments
+    ///
+    /// * `value`   - Value of the variable
+    pub fn new(value: AssignmentValue) -> Variable {
+        Variable {
+            value,
+            constant: false,
+            query: None
+        }
+    }
+}
+
+impl Environment {
+    /// Creates an environment holding only an empty global scope
+    pub fn new() -> Environment {
+        Environment {
+            scopes: vec![Scope::new()]
+        }
+    }
+
+    /// Gets the variables of the global scope
+    pub fn globals(&self) -> &Scope {
+        &self.scopes[0]
+    }
+
+    /// Gets the scopes of every block currently running, from the outermost
+    /// to the innermost. The global scope is not included.
+    pub fn locals(&self) -> &[Scope] {
+        &self.scopes[1..]
+    }
+
+    /// Pushes a scope that will be searched before every existing scope
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `scope`   - Scope to push, which may already hold variables
+    pub fn push_scope(&mut self, scope: Scope) {
+        self.scopes.push(scope);
+    }
+
+    /// Drops the innermost scope along with its variables. The global scope
+    /// is never dropped.
+    pub fn pop_scope(&mut self) -> Option<Scope> {
+        if self.scopes.len() > 1 {
+            self.scopes.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Gets the value of a variable from the innermost scope declaring it
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    pub fn get(&self, name: &str) -> Option<&AssignmentValue> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .map(|variable| &variable.value)
+    }
+
+    /// Assigns a value to a variable that is already declared, updating its
+    /// innermost declaration. Constants can't be assigned to.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    /// * `value`   - Value to assign
+    /// * `span`    - Location of the assignment in the script
+    pub fn assign(&mut self, name: &str, value: AssignmentValue, span: Span) -> Result<(), RuntimeError> {
+        let variable = self.get_assignable(name, span)?.ok_or_else(|| RuntimeError::UndefinedVariable {
+            name: name.to_string(),
+            span
+        })?;
+
+        *variable = Variable::new(value);
+        Ok(())
+    }
+
+    /// Assigns the result of a Stack statement to a variable. If an enclosing
+    /// scope already declares the variable, the innermost such declaration is
+    /// updated; otherwise the variable is declared in the innermost scope,
+    /// local to the current block. A result from a query keeps the query, so
+    /// the variable can be refreshed later.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    /// * `value`   - The result to assign
+    /// * `query`   - The query the result came from, if any
+    /// * `span`    - Location of the Stack statement in the script
+    pub fn assign_result(
+        &mut self,
+        name: &str,
+        value: AssignmentValue,
+        query: Option<Query>,
+        span: Span
+    ) -> Result<(), RuntimeError> {
+        let variable = Variable { query, ..Variable::new(value) };
+
+        match self.get_assignable(name, span)? {
+            Some(existing) => *existing = variable,
+            None => {
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(name.to_string(), variable);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets every variable bound to a query, from the outermost scope to the
+    /// innermost, along with the index of the scope holding it
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `first_scope` - Index of the outermost scope to include, where the global scope is 0
+    pub fn query_bindings(&self, first_scope: usize) -> Vec<(usize, String, Query)> {
+        let mut bindings = Vec::new();
+
+        for (index, scope) in self.scopes.iter().enumerate().skip(first_scope) {
+            for (name, variable) in scope {
+                if let Some(query) = &variable.query {
+                    bindings.push((index, name.clone(), query.clone()));
+                }
+            }
+        }
+
+        bindings
+    }
+
+    /// Replaces the value of a variable bound to a query with a new result
+    /// of the query
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `scope_index` - Index of the scope holding the variable
+    /// * `name`        - Name of the variable
+    /// * `value`       - The new result of the query
+    pub fn refresh(&mut self, scope_index: usize, name: &str, value: AssignmentValue) {
+        if let Some(variable) = self.scopes.get_mut(scope_index).and_then(|scope| scope.get_mut(name)) {
+            variable.value = value;
+        }
+    }
+
+    /// Gets the innermost declaration of a variable so it can be assigned to,
+    /// failing if it is a constant
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    /// * `span`    - Location of the assignment in the script
+    fn get_assignable(&mut self, name: &str, span: Span) -> Result<Option<&mut Variable>, RuntimeError> {
+        match self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name)) {
+            Some(variable) if variable.constant => Err(RuntimeError::ConstantReassignment {
+                name: name.to_string(),
+                span
+            }),
+            variable => Ok(variable)
+        }
+    }
+
+    /// Declares a new variable in the innermost scope. A variable can't be
+    /// declared with the name of one that is already in scope.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`        - Name of the variable
+    /// * `value`       - Value of the variable
+    /// * `constant`    - Whether the variable is a constant
+    /// * `span`        - Location of the declaration in the script
+    pub fn declare(&mut self, name: &str, value: AssignmentValue, constant: bool, span: Span) -> Result<(), RuntimeError> {
+        if self.get(name).is_some() {
+            return Err(RuntimeError::AlreadyDeclared {
+                name: name.to_string(),
+                span
+            });
+        }
+
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Variable { constant, ..Variable::new(value) });
+        }
+
+        Ok(())
+    }
+
+    /// Sets a variable in the global scope, as is done for the variables
+    /// provided by the host. Scripts can read these but not assign to them.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    /// * `value`   - Value of the variable
+    pub fn set_global(&mut self, name: &str, value: AssignmentValue) {
+        self.scopes[0].insert(name.to_string(), Variable { constant: true, ..Variable::new(value) });
+    }
+}
+
 impl ExecutionModel {
     /// Instantiates a new execution model
     pub fn new() -> ExecutionModel {
+        let mut environment = Environment::new();
+        environment.set_global("block_height", AssignmentValue::Number(Decimal::zero()));
+
         ExecutionModel {
-            assignments: HashMap::new()
+            environment,
+            payer: None,
+            rounding: RoundingMode::HalfEven,
+            fuel_limit: DEFAULT_FUEL_LIMIT,
+            fuel_used: 0,
+            expression_depth: 0,
+            block_height: 0,
+            triggers: Vec::new(),
+            transactions: Vec::new(),
+            mintable_assets: Vec::new()
         }
     }
 }
@@ -42,185 +320,1208 @@ impl Compiler {
         Compiler(ExecutionModel::new())
     } 
 
-    /// Parse through a heap expression
+    /// Executes each statement of a block in turn, in the current scope. The
+    /// script itself is run this way, so its variables are declared as globals.
     /// 
     /// ### Arguments
     /// 
-    /// * `heap_expression` - Heap expression to parse
-    pub fn parse_heap_expression(&mut self, heap_expression: &ParseNode) {
-        if heap_expression.entry == GrammarAtom::HeapExpression {
-            for node in &heap_expression.children {
+    /// * `block`   - Block of statements to execute
+    /// * `ledger`  - Ledger state read by queries
+    pub fn execute_block(&mut self, block: &Block, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        for statement in &block.statements {
+            self.consume_fuel(Operation::Statement, statement.span())?;
 
-                match node.entry {
-                    GrammarAtom::HeapKeyword(HeapKeyword::Stack) => {
-                        self.parse_stack_expression(node);
-                    }
-                    GrammarAtom::HeapKeyword(HeapKeyword::Set) => {
-                        self.perform_assignment(&node.children);
-                    }
-                    _ => {
-                        panic!("Unknown or unsupported Heap expression");
-                    }
+            match statement {
+                Stmt::Stack { statement, .. } => {
+                    self.execute_stack_statement(statement, ledger)?;
+                }
+                Stmt::Set { name, value, span } => {
+                    self.perform_assignment(name, value, *span)?;
+                }
+                Stmt::Let { name, value, span } => {
+                    let variable_value = self.evaluate_expression(value)?;
+                    self.0.environment.declare(name, variable_value, false, *span)?;
+                }
+                Stmt::Const { name, value, span } => {
+                    let constant_value = self.evaluate_expression(value)?;
+                    self.0.environment.declare(name, constant_value, true, *span)?;
+                }
+                Stmt::If { condition, then_block, else_block, .. } => {
+                    self.perform_conditional(condition, then_block, else_block.as_ref(), ledger)?;
+                }
+                Stmt::While { condition, block, span } => {
+                    self.perform_while_loop(condition, block, *span, ledger)?;
+                }
+                Stmt::For { variable, list, block, span } => {
+                    self.perform_for_loop(variable, list, block, *span, ledger)?;
+                }
+                Stmt::When { condition, block, span } => {
+                    self.register_trigger(condition, block, *span);
                 }
             }
+        }
 
-        } else {
-            panic!("Heap expression not declared with 'HeapExpression' grammar atom. Assuming this is an error and quitting...");
+        Ok(())
+    }
+
+    /// Executes a block in a new scope of its own, which is dropped along with
+    /// any variables declared in it once the block ends
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `block`   - Block of statements to execute
+    /// * `ledger`  - Ledger state read by queries
+    fn execute_scoped_block(&mut self, block: &Block, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        self.run_in_scopes(vec![Scope::new()], |compiler| compiler.execute_block(block, ledger))
+    }
+
+    /// Runs a function with the given scopes pushed onto the environment,
+    /// dropping them again afterwards even if the function fails
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `scopes`  - Scopes to push, from the outermost to the innermost
+    /// * `run`     - Function to run within the scopes
+    fn run_in_scopes<T>(
+        &mut self,
+        scopes: Vec<Scope>,
+        run: impl FnOnce(&mut Compiler) -> Result<T, ZqlError>
+    ) -> Result<T, ZqlError> {
+        let depth = scopes.len();
+
+        for scope in scopes {
+            self.0.environment.push_scope(scope);
+        }
+
+        let result = run(self);
+
+        for _ in 0..depth {
+            self.0.environment.pop_scope();
         }
+
+        result
     }
 
-    /// Parse through a stack expression
+    /// Executes a stack statement, adding the transactions it describes
+    /// to the execution model. Every statement uses its fuel before anything
+    /// else, then finds its payer, and only then resolves its operands, just
+    /// as operators use their fuel before evaluating their operands.
     /// 
     /// ### Arguments
     /// 
-    /// * `stack_expression`    - Stack expression to parse
-    pub fn parse_stack_expression(&mut self, stack_expression: &ParseNode) {
-        if stack_expression.entry == GrammarAtom::HeapKeyword(HeapKeyword::Stack) {
-            
-            
+    /// * `statement`   - Stack statement to execute
+    /// * `ledger`      - Ledger state read by queries
+    pub fn execute_stack_statement(&mut self, statement: &StackStmt, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        match statement {
+            StackStmt::Pay { payments, span } => {
+                for payment in payments {
+                    self.consume_fuel(Operation::StackEmission, payment.span)?;
+                }
 
-        } else {
-            panic!("Stack expression provided doesn't begin with a STACK keyword. Assuming this is an error and quitting...");
+                let payer = self.get_payer(*span)?;
+
+                // Every payment is built before the transaction is added, so a
+                // failing payment leaves none of the chain behind
+                let transfers = payments
+                    .iter()
+                    .map(|payment| self.build_transfer(payment).map(TransactionOperation::Transfer))
+                    .collect::<Result<Vec<TransactionOperation>, ZqlError>>()?;
+
+                self.0.transactions.push(TransactionIntent::new(payer, *span, transfers));
+
+                Ok(())
+            }
+            StackStmt::Get { target, query, span } => {
+                let result = self.perform_query(query, ledger)?;
+                self.0.environment.assign_result(target, result, Some(query.clone()), *span)?;
+
+                Ok(())
+            }
+            StackStmt::Create { key, content, encoding, span } => {
+                self.consume_fuel(Operation::StackEmission, *span)?;
+                let payer = self.get_payer(*span)?;
+
+                let operation = DataOperation::Create {
+                    key: self.resolve_text(key)?,
+                    content: self.resolve_content(content, *encoding)?,
+                    encoding: *encoding
+                };
+
+                self.add_data_operation(payer, operation, *span);
+
+                Ok(())
+            }
+            StackStmt::Update { key, content, encoding, predicates, span } => {
+                self.consume_fuel(Operation::StackEmission, *span)?;
+                let payer = self.get_payer(*span)?;
+
+                let operation = DataOperation::Update {
+                    key: self.resolve_text(key)?,
+                    content: self.resolve_content(content, *encoding)?,
+                    encoding: *encoding,
+                    owners: self.resolve_who_predicates(predicates)?
+                };
+
+                self.add_data_operation(payer, operation, *span);
+
+                Ok(())
+            }
+            StackStmt::Delete { key, predicates, span } => {
+                self.consume_fuel(Operation::StackEmission, *span)?;
+                let payer = self.get_payer(*span)?;
+
+                let operation = DataOperation::Delete {
+                    key: self.resolve_text(key)?,
+                    owners: self.resolve_who_predicates(predicates)?
+                };
+
+                self.add_data_operation(payer, operation, *span);
+
+                Ok(())
+            }
+            StackStmt::Mint { payee, amount, asset, span } => {
+                self.consume_fuel(Operation::StackEmission, *span)?;
+                let payer = self.get_payer(*span)?;
+
+                if !self.0.mintable_assets.contains(asset) {
+                    return Err(RuntimeError::MintNotPermitted {
+                        asset: asset.to_string(),
+                        span: *span
+                    }.into());
+                }
+
+                let mint = Transfer {
+                    payee: self.resolve_address(payee)?,
+                    amount: self.resolve_amount(amount, *asset)?,
+                    asset: *asset
+                };
+
+                self.0.transactions.push(TransactionIntent::new(payer, *span, vec![TransactionOperation::Mint(mint)]));
+
+                Ok(())
+            }
+            StackStmt::NewAddress { target, span } => {
+                self.consume_fuel(Operation::AddressCreation, *span)?;
+                let payer = self.get_payer(*span)?;
+
+                // Addresses are derived from the payer and a count of those it has
+                // already created, both on the ledger and by transactions not yet
+                // applied, so the same address is never created twice
+                let pending = self.0.transactions
+                    .iter()
+                    .filter(|transaction| transaction.payer == payer)
+                    .flat_map(|transaction| &transaction.operations)
+                    .filter(|operation| matches!(operation, TransactionOperation::CreateAddress(_)))
+                    .count() as u64;
+
+                let address = Address::derive(&payer, ledger.address_nonce(&payer) + pending);
+                let operations = vec![TransactionOperation::CreateAddress(address.clone())];
+
+                self.0.transactions.push(TransactionIntent::new(payer, *span, operations));
+                self.0.environment.assign_result(target, AssignmentValue::Address(address), None, *span)?;
+
+                Ok(())
+            }
+            StackStmt::Transact { statements, span } => self.perform_transaction(statements, *span, ledger)
         }
     }
 
-    /// Performs an assignment operation for the execution model
+    /// Performs a TRANSACT block, merging the operations of its statements, in
+    /// order, into a single transaction so the host performs all of them or
+    /// none. A statement that fails fails the whole script, which the kernel
+    /// then rolls back, so nothing the block did before the failure is kept.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `statements`  - Statements of the block
+    /// * `span`        - Location of the block in the script
+    /// * `ledger`      - Ledger state read by queries
+    fn perform_transaction(
+        &mut self,
+        statements: &[StackStmt],
+        span: Span,
+        ledger: &dyn Ledger
+    ) -> Result<(), ZqlError> {
+        let payer = self.get_payer(span)?;
+        let first = self.0.transactions.len();
+
+        for statement in statements {
+            self.execute_stack_statement(statement, ledger)?;
+        }
+
+        let operations = self.0.transactions
+            .drain(first..)
+            .flat_map(|intent| intent.operations)
+            .collect();
+
+        self.0.transactions.push(TransactionIntent::new(payer, span, operations));
+
+        Ok(())
+    }
+
+    /// Gets the address Stack statements pay from and act on behalf of,
+    /// failing if the host hasn't set one
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `span`    - Location of the statement in the script
+    fn get_payer(&self, span: Span) -> Result<Address, ZqlError> {
+        self.0.payer.clone().ok_or_else(|| RuntimeError::MissingPayer { span }.into())
+    }
+
+    /// Adds a transaction made up of a single data operation on behalf of the payer
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `payer`       - The address the operation is on behalf of
+    /// * `operation`   - The data operation to add
+    /// * `span`        - Location of the statement in the script
+    fn add_data_operation(&mut self, payer: Address, operation: DataOperation, span: Span) {
+        self.0.transactions.push(TransactionIntent::new(payer, span, vec![TransactionOperation::Data(operation)]));
+    }
+
+    /// Resolves the content of a data record, checking it is valid for its encoding
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - The text literal or variable holding the content
+    /// * `encoding`    - The encoding the content is stored with
+    fn resolve_content(&mut self, expression: &Expr, encoding: DataEncoding) -> Result<String, ZqlError> {
+        let content = self.resolve_text(expression)?;
+
+        if !encoding.is_valid(&content) {
+            return Err(RuntimeError::InvalidEncoding {
+                encoding: encoding.to_string(),
+                span: expression.span()
+            }.into());
+        }
+
+        Ok(content)
+    }
+
+    /// Resolves an expression that must produce text
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - The text literal or variable to resolve
+    fn resolve_text(&mut self, expression: &Expr) -> Result<String, ZqlError> {
+        match self.evaluate_expression(expression)? {
+            AssignmentValue::Text(t) => Ok(t),
+            other => Err(RuntimeError::TypeMismatch {
+                expected: String::from("text"),
+                found: format!("{:?}", other),
+                span: expression.span()
+            }.into())
+        }
+    }
+
+    /// Builds a transfer from a payment of the form `PAY <address> <amount> <asset>`.
+    /// The address and amount may be literals or previously assigned variables.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `payment` - The payment to build a transfer for
+    fn build_transfer(&mut self, payment: &Payment) -> Result<Transfer, ZqlError> {
+        Ok(Transfer {
+            payee: self.resolve_address(&payment.payee)?,
+            amount: self.resolve_amount(&payment.amount, payment.asset)?,
+            asset: payment.asset
+        })
+    }
+
+    /// Resolves the amount of an asset to pay or mint. Amounts are transferred
+    /// at the precision of the asset, so any extra places are rounded away
+    /// before checking the amount is greater than zero.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `amount`  - Expression for the amount
+    /// * `asset`   - The asset the amount is in
+    fn resolve_amount(&mut self, amount: &Expr, asset: Asset) -> Result<Decimal, ZqlError> {
+        let amount_value = self.calculate_expression(amount)?
+            .round_to(asset.precision(), self.0.rounding)
+            .map_err(|e| RuntimeError::from_decimal_error(e, amount.span()))?;
+
+        if amount_value.is_zero() || amount_value.is_negative() {
+            return Err(RuntimeError::InvalidAmount {
+                amount: amount_value.to_string(),
+                span: amount.span()
+            }.into());
+        }
+
+        Ok(amount_value)
+    }
+
+    /// Performs a read query against the ledger. An AMOUNT query gives the total
+    /// balance of the matching addresses, in ZNT unless another asset is named.
+    /// A TRANSACT query gives the amounts of the matching transfers paid or
+    /// received by those addresses, oldest first, with each transfer only
+    /// counted once even if it is between two of the addresses.
     /// 
     /// ### Arguments
     /// 
-    /// * `expression` - Assignment expression to parse and perform
-    fn perform_assignment(&mut self, expression: &Vec<ParseNode>) {
-        // Only 1 ParseNode should be present in an assignment expression
-        if expression.len() == 1 {
-            let full_expression = &expression[0].children;
+    /// * `query`   - The query to perform
+    /// * `ledger`  - Ledger state to read
+    fn perform_query(&mut self, query: &Query, ledger: &dyn Ledger) -> Result<AssignmentValue, ZqlError> {
+        let unique_addresses = self.resolve_who_predicates(&query.predicates)?;
+        let mut amount_filters = Vec::new();
 
-            // An assignment expr should have left hand side, a To operator, and a right hand side
-            if full_expression.len() > 3 {
-                let mut right_hand_side = 0;
+        for predicate in &query.predicates {
+            if let Predicate::Amount { op, value, .. } = predicate {
+                amount_filters.push((op, self.calculate_expression(value)?));
+            }
+        }
+
+        let matches_filters = |amount: Decimal| amount_filters.iter().all(|(op, value)| match op {
+            OpAtom::EqualTo => amount == *value,
+            OpAtom::NotEqualTo => amount != *value,
+            OpAtom::LessThan => amount < *value,
+            OpAtom::GreaterThan => amount > *value,
+            OpAtom::LessThanOrEqualTo => amount <= *value,
+            OpAtom::GreaterThanOrEqualTo => amount >= *value,
+            _ => false
+        });
+
+        match query.field {
+            QueryField::Amount => {
+                let asset = query.asset.unwrap_or(Asset::Znt);
+                let mut total = Decimal::zero();
 
-                for (index, atom) in full_expression.iter().enumerate() {
-                    if atom.entry == GrammarAtom::Op(OpAtom::To) {
-                        right_hand_side = index + 1;
+                for address in &unique_addresses {
+                    self.consume_fuel(Operation::LedgerQuery, query.span)?;
+                    let balance = ledger.balance(address, asset);
+
+                    if matches_filters(balance) {
+                        total = total
+                            .checked_add(balance)
+                            .map_err(|e| RuntimeError::from_decimal_error(e, query.span))?;
                     }
                 }
 
-                let calculated_right_hand_side = self.calculate_expression(&full_expression[right_hand_side..]);
-                let right_hand_side_value = AssignmentValue::Number(calculated_right_hand_side);
-                
-                match &full_expression[0].entry {
-                    GrammarAtom::Value(key) => {
-                        self.0.assignments.insert(key.to_string(), right_hand_side_value);
-                    }
-                    _ => {
-                        panic!("Left hand side of assignment not valid");
-                    }
+                Ok(AssignmentValue::Number(total))
+            }
+            QueryField::Transact => {
+                let mut entries = Vec::new();
+
+                for address in &unique_addresses {
+                    self.consume_fuel(Operation::LedgerQuery, query.span)?;
+                    entries.extend(ledger.history(address));
                 }
 
-            } else {
-                // It's a simple assignment process
-                match &full_expression[0].entry {
-                    GrammarAtom::Value(key) => {
-                        let value = match &full_expression[2].entry {
-                            GrammarAtom::Value(v) => AssignmentValue::Text(v.clone()),
-                            GrammarAtom::Number(v) => AssignmentValue::Number(v.clone()),
-                            _ => {
-                                panic!("Unsupported or invalid right hand side of variable assignment: {:?}", full_expression[2].entry);
+                entries.retain(|entry: &LedgerEntry| {
+                    query.asset.map_or(true, |asset| entry.asset == asset) && matches_filters(entry.amount)
+                });
+
+                entries.sort_by_key(|entry| entry.sequence);
+                entries.dedup_by_key(|entry| entry.sequence);
+
+                Ok(AssignmentValue::List(
+                    entries.into_iter().map(|entry| AssignmentValue::Number(entry.amount)).collect()
+                ))
+            }
+        }
+    }
+
+    /// Resolves the addresses named by the WHO predicates of a WHERE clause.
+    /// An address named more than once is only given once.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `predicates`  - Predicates of the WHERE clause
+    fn resolve_who_predicates(&mut self, predicates: &[Predicate]) -> Result<Vec<Address>, ZqlError> {
+        let mut addresses = Vec::new();
+
+        for predicate in predicates {
+            match predicate {
+                Predicate::WhoIs { address, .. } => addresses.push(self.resolve_address(address)?),
+                Predicate::WhoIn { addresses: list, .. } => {
+                    let items = match self.evaluate_expression(list)? {
+                        AssignmentValue::List(items) => items,
+                        other => {
+                            return Err(RuntimeError::TypeMismatch {
+                                expected: String::from("a list of addresses"),
+                                found: format!("{:?}", other),
+                                span: list.span()
+                            }.into());
+                        }
+                    };
+
+                    for item in items {
+                        match item {
+                            AssignmentValue::Address(a) => addresses.push(a),
+                            other => {
+                                return Err(RuntimeError::TypeMismatch {
+                                    expected: String::from("an address"),
+                                    found: format!("{:?}", other),
+                                    span: list.span()
+                                }.into());
                             }
-                        };
-    
-                        self.0.assignments.insert(key.to_string(), value);
-                    }
-                    _ => {
-                        panic!("Left hand side of assignment not valid");
+                        }
                     }
                 }
+                Predicate::Amount { .. } => {}
             }
+        }
 
-        } else {
-            panic!("Assigning a variable failed. There should be exactly 1 expression in SET statement");
+        let mut unique_addresses: Vec<Address> = Vec::new();
+
+        for address in addresses {
+            if !unique_addresses.contains(&address) {
+                unique_addresses.push(address);
+            }
         }
+
+        Ok(unique_addresses)
+    }
+
+    /// Resolves an expression that must produce an address
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - The address literal or variable to resolve
+    fn resolve_address(&mut self, expression: &Expr) -> Result<Address, ZqlError> {
+        match self.evaluate_expression(expression)? {
+            AssignmentValue::Address(a) => Ok(a),
+            other => Err(RuntimeError::TypeMismatch {
+                expected: String::from("an address"),
+                found: format!("{:?}", other),
+                span: expression.span()
+            }.into())
+        }
+    }
+
+    /// Performs an assignment operation for the execution model
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable to assign
+    /// * `value`   - Expression for the value to assign
+    /// * `span`    - Location of the assignment in the script
+    fn perform_assignment(&mut self, name: &str, value: &Expr, span: Span) -> Result<(), ZqlError> {
+        let right_hand_side_value = self.evaluate_expression(value)?;
+        self.0.environment.assign(name, right_hand_side_value, span)?;
+
+        Ok(())
     } 
 
-    /// Calculates an arbitrary mathematical expression to return a single output value
+    /// Performs a conditional, running the block for whichever branch applies.
+    /// Any stack statements in a branch that isn't taken are never executed,
+    /// so they add no transactions.
     /// 
     /// ### Arguments
     /// 
-    /// * `expression`  - Expression to calculate
-    fn calculate_expression(&self, expression: &[ParseNode]) -> f64 {
-        let mut value = 0.0;
-        let mut previous_operator: Option<OpAtom> = None;
+    /// * `condition`   - Condition deciding which branch to take
+    /// * `then_block`  - Block to run when the condition holds
+    /// * `else_block`  - Block to run otherwise, if any
+    /// * `ledger`      - Ledger state read by queries
+    fn perform_conditional(
+        &mut self,
+        condition: &Expr,
+        then_block: &Block,
+        else_block: Option<&Block>,
+        ledger: &dyn Ledger
+    ) -> Result<(), ZqlError> {
+        if self.evaluate_condition(condition)? {
+            self.execute_scoped_block(then_block, ledger)
+        } else if let Some(block) = else_block {
+            self.execute_scoped_block(block, ledger)
+        } else {
+            Ok(())
+        }
+    }
 
-        for atom in expression {
-            match &atom.entry {
-                GrammarAtom::Number(a) => {
-                    if previous_operator.is_none() {
-                        value += a;
-                    } else {
-                        self.update_value_from_operator(&mut value, a, &previous_operator.unwrap());
-                        previous_operator = None;
-                    }
-                }
-                GrammarAtom::Op(a) => {
-                    previous_operator = Some(a.clone());
-                }
-                GrammarAtom::Value(a) => {
-                    // In the case of a generic value, we assume it is a previously assigned variable
-                    let value_from_model = self.0.assignments.get(a);
-                    
-                    if value_from_model.is_some() {
-                        let final_value_from_model = match value_from_model.unwrap() {
-                            AssignmentValue::Number(f) => f,
-                            _ => { panic!("Value from script model is invalid"); }
-                        };
+    /// Performs a while loop, running its block for as long as the condition holds
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `condition`   - Condition checked before each iteration
+    /// * `block`       - Block to run on each iteration
+    /// * `span`        - Location of the loop in the script
+    /// * `ledger`      - Ledger state read by queries
+    fn perform_while_loop(&mut self, condition: &Expr, block: &Block, span: Span, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        while self.evaluate_condition(condition)? {
+            self.consume_fuel(Operation::Iteration, span)?;
+            self.execute_scoped_block(block, ledger)?;
+        }
 
-                        self.update_value_from_operator(&mut value, final_value_from_model, &previous_operator.unwrap());
-                        previous_operator = None;
+        Ok(())
+    }
 
-                    } else {
-                        println!("");
-                        println!("Variable '{}' is not assigned", a);
-                        println!("");
+    /// Performs a for loop, assigning each item of a list to the loop variable
+    /// in turn and running the block for it. The list is evaluated once, before
+    /// the first iteration. The loop variable is declared in the scope of the
+    /// block and dropped along with it. Like any other declaration, it can't
+    /// take the name of a variable that is already in scope.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `variable`    - Name of the loop variable
+    /// * `list`        - Expression for the list to loop over
+    /// * `block`       - Block to run for each item
+    /// * `span`        - Location of the loop in the script
+    /// * `ledger`      - Ledger state read by queries
+    fn perform_for_loop(
+        &mut self,
+        variable: &str,
+        list: &Expr,
+        block: &Block,
+        span: Span,
+        ledger: &dyn Ledger
+    ) -> Result<(), ZqlError> {
+        let items = match self.evaluate_expression(list)? {
+            AssignmentValue::List(items) => items,
+            other => {
+                return Err(RuntimeError::TypeMismatch {
+                    expected: String::from("a list"),
+                    found: format!("{:?}", other),
+                    span: list.span()
+                }.into());
+            }
+        };
 
-                        panic!("Variable '{}' is not assigned", a);
-                    }
-                }
-                _ => {
-                    panic!("Grammar atom '{:?}' for expression calculation invalid or unsupported", atom.entry);
+        for item in items {
+            self.consume_fuel(Operation::Iteration, span)?;
+
+            self.run_in_scopes(vec![Scope::new()], |compiler| {
+                compiler.0.environment.declare(variable, item, false, span)?;
+                compiler.execute_block(block, ledger)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a WHEN block as a trigger. The block isn't run here; the kernel
+    /// evaluates triggers whenever the state they may depend on changes.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `condition`   - Condition the trigger waits for
+    /// * `block`       - Block to run once the condition holds
+    /// * `span`        - Location of the WHEN statement in the script
+    fn register_trigger(&mut self, condition: &Expr, block: &Block, span: Span) {
+        self.0.triggers.push(Trigger {
+            condition: condition.clone(),
+            block: block.clone(),
+            locals: self.0.environment.locals().to_vec(),
+            span
+        });
+    }
+
+    /// Sets the current block height, which scripts can read as `block_height`.
+    /// Heights too large for a script number are rejected and leave the current
+    /// height unchanged.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `block_height`    - The new block height
+    pub fn set_block_height(&mut self, block_height: u64) -> Result<(), ZqlError> {
+        let height = i64::try_from(block_height)
+            .map_err(|_| RuntimeError::Overflow { span: Span::default() })?;
+
+        self.0.block_height = block_height;
+        self.0.environment.set_global("block_height", AssignmentValue::Number(Decimal::from_int(height)));
+
+        Ok(())
+    }
+
+    /// Evaluates every registered trigger in the order they were registered,
+    /// running the block of each one whose condition now holds. A trigger only
+    /// ever fires once, so it is removed before its block runs.
+    /// 
+    /// Before any condition is evaluated, every variable assigned by GET is
+    /// refreshed by running its query against the ledger again, so conditions
+    /// can wait on balances and history. Only transactions already applied to
+    /// the ledger are seen, so when triggers are evaluated straight after a
+    /// script runs, the script's own transactions are not yet included.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `ledger`  - Ledger state read by queries
+    pub fn evaluate_triggers(&mut self, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        if self.0.triggers.is_empty() {
+            return Ok(());
+        }
+
+        self.refresh_queries(0, ledger)?;
+        let mut index = 0;
+
+        while index < self.0.triggers.len() {
+            let trigger = self.0.triggers[index].clone();
+
+            let fired = self.run_in_scopes(trigger.locals.clone(), |compiler| {
+                // The global scope has already been refreshed
+                compiler.refresh_queries(1, ledger)?;
+
+                if !compiler.evaluate_condition(&trigger.condition)? {
+                    return Ok(false);
                 }
+
+                compiler.0.triggers.remove(index);
+                compiler.execute_scoped_block(&trigger.block, ledger)?;
+
+                Ok(true)
+            })?;
+
+            if !fired {
+                index += 1;
             }
         }
 
-        value
+        Ok(())
+    }
+
+    /// Runs the query of every variable bound to one again, replacing the value
+    /// of the variable with the new result
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `first_scope` - Index of the outermost scope to refresh, where the global scope is 0
+    /// * `ledger`      - Ledger state read by queries
+    fn refresh_queries(&mut self, first_scope: usize, ledger: &dyn Ledger) -> Result<(), ZqlError> {
+        for (scope_index, name, query) in self.0.environment.query_bindings(first_scope) {
+            let value = self.perform_query(&query, ledger)?;
+            self.0.environment.refresh(scope_index, &name, value);
+        }
+
+        Ok(())
+    }
+
+    /// Uses the fuel an operation costs, failing if the execution doesn't have
+    /// enough fuel left for it so scripts can never run forever. Fuel used by
+    /// an operation that fails for lack of fuel is not counted.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `operation`   - The operation to use fuel for
+    /// * `span`        - Location of the operation in the script
+    fn consume_fuel(&mut self, operation: Operation, span: Span) -> Result<(), ZqlError> {
+        let fuel_used = self.0.fuel_used.saturating_add(get_fuel_cost(operation));
+
+        if fuel_used > self.0.fuel_limit {
+            return Err(RuntimeError::OutOfFuel {
+                limit: self.0.fuel_limit,
+                span
+            }.into());
+        }
+
+        self.0.fuel_used = fuel_used;
+        Ok(())
+    }
+
+    /// Evaluates the condition of a conditional, loop or trigger, which must
+    /// produce a boolean
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `condition`   - Condition expression to evaluate
+    fn evaluate_condition(&mut self, condition: &Expr) -> Result<bool, ZqlError> {
+        match self.evaluate_expression(condition)? {
+            AssignmentValue::Bool(b) => Ok(b),
+            other => Err(RuntimeError::TypeMismatch {
+                expected: String::from("a boolean"),
+                found: format!("{:?}", other),
+                span: condition.span()
+            }.into())
+        }
     }
 
-    /// Updates the current value depending on the previous operator
+    /// Evaluates an expression to a value of whichever type it produces
     /// 
     /// ### Arguments
     /// 
-    /// * `current_value`   - Current value to update
-    /// * `new_value`       - New value to update the current value with
-    /// * `prev_operator`   - The previous operator, to determine the nature of the update
-    fn update_value_from_operator(&self, current_value: &mut f64, new_value: &f64, prev_operator: &OpAtom) {
-        match prev_operator {
-            OpAtom::Add => {
-                *current_value += new_value;
+    /// * `expression`  - Expression to evaluate
+    fn evaluate_expression(&mut self, expression: &Expr) -> Result<AssignmentValue, ZqlError> {
+        match expression {
+            Expr::Text { value, .. } => Ok(AssignmentValue::Text(value.clone())),
+            Expr::Bool { value, .. } => Ok(AssignmentValue::Bool(*value)),
+            Expr::Address { value, .. } => Ok(AssignmentValue::Address(value.clone())),
+            Expr::Variable { name, span } => Ok(self.get_variable(name, *span)?.clone()),
+            Expr::List { items, span } => self.evaluate_nested(*span, |compiler| {
+                let values = items
+                    .iter()
+                    .map(|item| compiler.evaluate_expression(item))
+                    .collect::<Result<Vec<AssignmentValue>, ZqlError>>()?;
+
+                Ok(AssignmentValue::List(values))
+            }),
+            Expr::Unary { op, .. } | Expr::Binary { op, .. } if is_boolean_operator(op) => {
+                Ok(AssignmentValue::Bool(self.evaluate_boolean_operator(expression)?))
             }
-            OpAtom::Multiply => {
-                *current_value *= new_value;
+            _ => Ok(AssignmentValue::Number(self.calculate_expression(expression)?))
+        }
+    }
+
+    /// Evaluates a comparison or logical operator. `and` and `or` short-circuit,
+    /// so their right hand operand is only evaluated when it decides the result.
+    /// `<`, `>`, `<=` and `>=` compare numbers, while `==` and `!=` compare any
+    /// two values of the same type.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - Operator expression to evaluate
+    fn evaluate_boolean_operator(&mut self, expression: &Expr) -> Result<bool, ZqlError> {
+        self.consume_fuel(Operation::Comparison, expression.span())?;
+        self.evaluate_nested(expression.span(), |compiler| compiler.apply_boolean_operator(expression))
+    }
+
+    /// Applies a comparison or logical operator to its operands, which are
+    /// evaluated as the operator requires
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - Operator expression to apply
+    fn apply_boolean_operator(&mut self, expression: &Expr) -> Result<bool, ZqlError> {
+        match expression {
+            Expr::Unary { op: OpAtom::Not, operand, .. } => Ok(!self.evaluate_condition(operand)?),
+            Expr::Binary { op, left, right, span } => match op {
+                OpAtom::And => Ok(self.evaluate_condition(left)? && self.evaluate_condition(right)?),
+                OpAtom::Or => Ok(self.evaluate_condition(left)? || self.evaluate_condition(right)?),
+                OpAtom::EqualTo => self.evaluate_equality(left, right),
+                OpAtom::NotEqualTo => Ok(!self.evaluate_equality(left, right)?),
+                _ => {
+                    let left_value = self.calculate_expression(left)?;
+                    let right_value = self.calculate_expression(right)?;
+
+                    match op {
+                        OpAtom::LessThan => Ok(left_value < right_value),
+                        OpAtom::GreaterThan => Ok(left_value > right_value),
+                        OpAtom::LessThanOrEqualTo => Ok(left_value <= right_value),
+                        OpAtom::GreaterThanOrEqualTo => Ok(left_value >= right_value),
+                        _ => Err(RuntimeError::InvalidOperator {
+                            operator: format!("{:?}", op),
+                            span: *span
+                        }.into())
+                    }
+                }
+            },
+            _ => Err(RuntimeError::TypeMismatch {
+                expected: String::from("a comparison"),
+                found: format!("{:?}", expression),
+                span: expression.span()
+            }.into())
+        }
+    }
+
+    /// Checks whether two operands hold equal values. Values of different
+    /// types can't be compared.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `left`    - Left hand operand
+    /// * `right`   - Right hand operand
+    fn evaluate_equality(&mut self, left: &Expr, right: &Expr) -> Result<bool, ZqlError> {
+        let left_value = self.evaluate_expression(left)?;
+        let right_value = self.evaluate_expression(right)?;
+
+        if mem::discriminant(&left_value) != mem::discriminant(&right_value) {
+            return Err(RuntimeError::TypeMismatch {
+                expected: format!("a value comparable to {:?}", left_value),
+                found: format!("{:?}", right_value),
+                span: right.span()
+            }.into());
+        }
+
+        Ok(left_value == right_value)
+    }
+
+    /// Gets the value of a previously assigned variable
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `name`    - Name of the variable
+    /// * `span`    - Location of the variable reference in the script
+    fn get_variable(&mut self, name: &str, span: Span) -> Result<&AssignmentValue, ZqlError> {
+        self.consume_fuel(Operation::VariableLookup, span)?;
+
+        self.0.environment.get(name).ok_or_else(|| RuntimeError::UndefinedVariable {
+            name: name.to_string(),
+            span
+        }.into())
+    }
+
+    /// Calculates an arbitrary mathematical expression tree to return a single output value.
+    /// Operators hold their operands directly, so precedence is already
+    /// resolved by the shape of the tree.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `expression`  - Expression tree to calculate
+    fn calculate_expression(&mut self, expression: &Expr) -> Result<Decimal, ZqlError> {
+        match expression {
+            Expr::Number { value, .. } => Ok(*value),
+            Expr::Variable { name, span } => {
+                // Identifiers always refer to a previously assigned variable
+                match self.get_variable(name, *span)? {
+                    AssignmentValue::Number(f) => Ok(*f),
+                    other => Err(RuntimeError::TypeMismatch {
+                        expected: String::from("a number"),
+                        found: format!("{:?}", other),
+                        span: *span
+                    }.into())
+                }
             }
-            OpAtom::Subtract => {
-                *current_value -= new_value;
+            Expr::Unary { op: OpAtom::Subtract, operand, span } => {
+                // Fuel is used before the operand is calculated, so a script runs
+                // out of fuel at the same point however deeply it is nested
+                self.consume_fuel(Operation::Arithmetic, *span)?;
+                let value = self.evaluate_nested(*span, |compiler| compiler.calculate_expression(operand))?;
+
+                value.checked_neg().map_err(|e| RuntimeError::from_decimal_error(e, *span).into())
             }
-            OpAtom::Divide => {
-                *current_value /= new_value;
+            Expr::Binary { op, left, right, span } if !is_boolean_operator(op) => {
+                self.consume_fuel(Operation::Arithmetic, *span)?;
+
+                let (left_value, right_value) = self.evaluate_nested(*span, |compiler| {
+                    Ok((compiler.calculate_expression(left)?, compiler.calculate_expression(right)?))
+                })?;
+
+                self.apply_operator(left_value, right_value, op, *span)
             }
-            _ => {
-                println!("");
-                println!("Cannot calculate an updated value due to invalid operator: {:?}", prev_operator);
-                println!("");
+            Expr::Unary { op, span, .. } if !is_boolean_operator(op) => Err(RuntimeError::InvalidOperator {
+                operator: format!("{:?}", op),
+                span: *span
+            }.into()),
+            // Anything else can't produce a number. What it would produce is
+            // described from the syntax tree, as evaluating it would use fuel
+            // just to report the error.
+            Expr::Unary { span, .. } | Expr::Binary { span, .. } => Err(self.not_a_number("a boolean", *span)),
+            Expr::Bool { span, .. } => Err(self.not_a_number("a boolean", *span)),
+            Expr::Text { span, .. } => Err(self.not_a_number("text", *span)),
+            Expr::Address { span, .. } => Err(self.not_a_number("an address", *span)),
+            Expr::List { span, .. } => Err(self.not_a_number("a list", *span))
+        }
+    }
+
+    /// Builds the error for an expression that had to produce a number but can't
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `found`   - Description of what the expression produces instead
+    /// * `span`    - Location of the expression in the script
+    fn not_a_number(&self, found: &str, span: Span) -> ZqlError {
+        RuntimeError::TypeMismatch {
+            expected: String::from("a number"),
+            found: String::from(found),
+            span
+        }.into()
+    }
+
+    /// Evaluates the operands of an operator or the items of a list, one level
+    /// deeper than the expression holding them. Scripts are checked for nesting
+    /// when they are parsed, but evaluation is limited as well so that it can
+    /// never exhaust the stack.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `span`        - Location of the expression holding the operands
+    /// * `evaluate`    - Evaluates the operands
+    fn evaluate_nested<T>(
+        &mut self,
+        span: Span,
+        evaluate: impl FnOnce(&mut Compiler) -> Result<T, ZqlError>
+    ) -> Result<T, ZqlError> {
+        if self.0.expression_depth >= MAX_NESTING_DEPTH {
+            return Err(RuntimeError::NestingTooDeep {
+                limit: MAX_NESTING_DEPTH,
+                span
+            }.into());
+        }
+
+        self.0.expression_depth += 1;
+        let result = evaluate(self);
+        self.0.expression_depth -= 1;
 
-                panic!("Cannot calculate an updated value due to invalid operator: {:?}", prev_operator);
+        result
+    }
+
+    /// Applies a binary operator to its two calculated operands. Multiplication
+    /// and division round any places beyond the fixed decimal scale using the
+    /// rounding mode of the execution model. The fuel for the operator has
+    /// already been used by the time its operands are calculated.
+    /// 
+    /// ### Arguments
+    /// 
+    /// * `left_value`      - Value of the left hand operand
+    /// * `right_value`     - Value of the right hand operand
+    /// * `operator`        - The operator to apply to the operands
+    /// * `span`            - Location of the operator in the script
+    fn apply_operator(&mut self, left_value: Decimal, right_value: Decimal, operator: &OpAtom, span: Span) -> Result<Decimal, ZqlError> {
+        let result = match operator {
+            OpAtom::Add => left_value.checked_add(right_value),
+            OpAtom::Multiply => left_value.checked_mul(right_value, self.0.rounding),
+            OpAtom::Subtract => left_value.checked_sub(right_value),
+            OpAtom::Divide => left_value.checked_div(right_value, self.0.rounding),
+            _ => {
+                return Err(RuntimeError::InvalidOperator {
+                    operator: format!("{:?}", operator),
+                    span
+                }.into());
             }
+        };
+
+        result.map_err(|e| RuntimeError::from_decimal_error(e, span).into())
+    }
+}
+
+/// Checks whether an operator produces a boolean rather than a number
+///
+/// ### Arguments
+///
+/// * `operator`    - The operator to check
+fn is_boolean_operator(operator: &OpAtom) -> bool {
+    matches!(
+        operator,
+        OpAtom::LessThan
+            | OpAtom::GreaterThan
+            | OpAtom::LessThanOrEqualTo
+            | OpAtom::GreaterThanOrEqualTo
+            | OpAtom::EqualTo
+            | OpAtom::NotEqualTo
+            | OpAtom::And
+            | OpAtom::Or
+            | OpAtom::Not
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::error::{RuntimeError, SemanticError, ZqlError};
+    use crate::grammar::{OpAtom, MAX_NESTING_DEPTH};
+    use crate::ledger::InMemoryLedger;
+    use crate::lexer::Span;
+    use crate::syntax::{Block, Expr, Parser, Stmt};
+    use super::{AssignmentValue, Compiler, Environment, Scope};
+
+    fn span() -> Span {
+        Span { start: 0, end: 0, line: 1, column: 1 }
+    }
+
+    fn number(value: i64) -> AssignmentValue {
+        AssignmentValue::Number(Decimal::from_int(value))
+    }
+
+    /// Parses and executes a whole script against an empty ledger
+    fn execute(script: &str) -> Result<Compiler, ZqlError> {
+        let mut compiler = Compiler::new();
+        compiler.execute_block(&Parser.parse_script(script)?, &InMemoryLedger::new())?;
+
+        Ok(compiler)
+    }
+
+    /// Builds `1 + 1 + ... + 1` with the given number of operators, nested to
+    /// the left as the parser would nest it
+    fn sum(operators: usize) -> Expr {
+        let one = || Expr::Number { value: Decimal::from_int(1), span: span() };
+
+        (0..operators).fold(one(), |left, _| Expr::Binary {
+            op: OpAtom::Add,
+            left: Box::new(left),
+            right: Box::new(one()),
+            span: span()
+        })
+    }
+
+    #[test]
+    fn nesting_allowed_by_the_parser_can_be_evaluated() {
+        let script = format!("let x = {}1;", "-".repeat(MAX_NESTING_DEPTH - 1));
+        let mut compiler = Compiler::new();
+
+        compiler.execute_block(&Parser.parse_script(&script).unwrap(), &InMemoryLedger::new()).unwrap();
+
+        assert_eq!(compiler.0.environment.get("x"), Some(&number(-1)));
+        assert_eq!(compiler.calculate_expression(&sum(MAX_NESTING_DEPTH - 1)).unwrap(), Decimal::from_int(64));
+    }
+
+    #[test]
+    fn evaluation_is_limited_in_depth() {
+        let mut compiler = Compiler::new();
+
+        let result = compiler.calculate_expression(&sum(MAX_NESTING_DEPTH + 1));
+
+        match result {
+            Err(ZqlError::Runtime(RuntimeError::NestingTooDeep { limit, .. })) => assert_eq!(limit, MAX_NESTING_DEPTH),
+            other => panic!("expected the nesting to be too deep, found {:?}", other),
         }
+
+        assert_eq!(compiler.0.expression_depth, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn stack_statements_use_fuel_before_anything_else() {
+        let statements = [
+            "stack [ PAY x 1 ZNT AND PAY x 2 SDL ]",
+            "stack [ CREATE x 'a' ENCODING utf8 ]",
+            "stack [ UPDATE x 'a' ENCODING utf8 WHERE WHO = x ]",
+            "stack [ DELETE x WHERE WHO = x ]",
+            "stack [ NEW SDL 1 TO x ]",
+            "stack [ y = NEW ADDRESS ]"
+        ];
+
+        for source in statements {
+            let block = Parser.parse_script(&format!("let x = true; {}", source)).unwrap();
+            let statement = match &block.statements[1] {
+                Stmt::Stack { statement, .. } => statement,
+                other => panic!("expected a stack statement, found {:?}", other)
+            };
+
+            // Without a payer, or with operands of the wrong type, the statement
+            // could only fail, but it runs out of fuel before finding that out
+            let mut compiler = Compiler::new();
+            compiler.execute_block(&Block { statements: vec![block.statements[0].clone()], span: span() }, &InMemoryLedger::new()).unwrap();
+            compiler.0.fuel_limit = compiler.0.fuel_used;
+
+            let result = compiler.execute_stack_statement(statement, &InMemoryLedger::new());
+
+            assert!(matches!(result, Err(ZqlError::Runtime(RuntimeError::OutOfFuel { .. }))), "{}: {:?}", source, result);
+        }
+    }
+
+    #[test]
+    fn numbers_are_not_calculated_from_other_literals() {
+        let literals = ["true", "'one'", "[1]"];
+
+        for literal in literals {
+            let error = execute(&format!("let x = 1 + {};", literal)).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::TypeMismatch { .. })), "{}: {:?}", literal, error);
+        }
+    }
+
+    #[test]
+    fn fuel_is_used_before_operands_are_evaluated() {
+        let mut compiler = Compiler::new();
+        compiler.0.fuel_limit = 10;
+
+        let result = compiler.calculate_expression(&sum(MAX_NESTING_DEPTH + 1));
+
+        assert!(matches!(result, Err(ZqlError::Runtime(RuntimeError::OutOfFuel { .. }))));
+        assert_eq!(compiler.0.fuel_used, 10);
+        assert_eq!(compiler.0.expression_depth, 0);
+    }
+
+    #[test]
+    fn constants_cannot_be_assigned() {
+        let mut environment = Environment::new();
+        environment.declare("c", number(1), true, span()).unwrap();
+
+        let result = environment.assign("c", number(2), span());
+        let result_from_query = environment.assign_result("c", number(2), None, span());
+
+        assert!(matches!(result, Err(RuntimeError::ConstantReassignment { .. })));
+        assert!(matches!(result_from_query, Err(RuntimeError::ConstantReassignment { .. })));
+        assert_eq!(environment.get("c"), Some(&number(1)));
+    }
+
+    #[test]
+    fn host_globals_are_constant() {
+        let mut environment = Environment::new();
+        environment.set_global("block_height", number(7));
+
+        let result = environment.assign("block_height", number(8), span());
+
+        assert!(matches!(result, Err(RuntimeError::ConstantReassignment { .. })));
+    }
+
+    #[test]
+    fn block_heights_too_large_for_a_number_are_rejected() {
+        let mut compiler = Compiler::new();
+        compiler.set_block_height(i64::MAX as u64).unwrap();
+
+        let result = compiler.set_block_height(i64::MAX as u64 + 1);
+
+        assert!(matches!(result, Err(ZqlError::Runtime(RuntimeError::Overflow { .. }))));
+        assert_eq!(compiler.0.block_height, i64::MAX as u64);
+        assert_eq!(compiler.0.environment.get("block_height"), Some(&number(i64::MAX)));
+    }
+
+    #[test]
+    fn variables_must_be_declared_before_assignment() {
+        let mut environment = Environment::new();
+
+        let result = environment.assign("x", number(1), span());
+
+        assert!(matches!(result, Err(RuntimeError::UndefinedVariable { .. })));
+        assert_eq!(environment.get("x"), None);
+    }
+
+    #[test]
+    fn variables_cannot_be_declared_twice() {
+        let mut environment = Environment::new();
+        environment.declare("x", number(1), false, span()).unwrap();
+        environment.push_scope(Scope::new());
+
+        let result = environment.declare("x", number(2), false, span());
+
+        assert!(matches!(result, Err(RuntimeError::AlreadyDeclared { .. })));
+    }
+
+    #[test]
+    fn loop_variables_cannot_shadow_constants() {
+        let mut compiler = Compiler::new();
+        compiler.0.environment.declare("x", number(1), true, span()).unwrap();
+        let script = Parser.parse_script("for x in [2] { set x = 3; }").unwrap();
+
+        let result = compiler.execute_block(&script, &InMemoryLedger::new());
+
+        assert!(matches!(result, Err(ZqlError::Runtime(RuntimeError::AlreadyDeclared { .. }))));
+        assert_eq!(compiler.0.environment.get("x"), Some(&number(1)));
+    }
+
+    #[test]
+    fn blocks_can_update_the_variables_around_them() {
+        let compiler = execute("let x = 1; if true { set x = 2; } while x < 5 { set x = x + 1; }").unwrap();
+
+        assert_eq!(compiler.0.environment.get("x"), Some(&number(5)));
+    }
+
+    #[test]
+    fn block_variables_are_dropped_when_the_block_ends() {
+        let script = "
+            let total = 0;
+
+            for amount in [1, 2, 3] {
+                let doubled = amount * 2;
+                set total = total + doubled;
+            }
+
+            let doubled = total;
+        ";
+        let compiler = execute(script).unwrap();
+        let read_after_block = execute("if true { let y = 1; } let z = y;");
+
+        assert_eq!(compiler.0.environment.get("doubled"), Some(&number(12)));
+        assert_eq!(compiler.0.environment.get("amount"), None);
+        assert!(matches!(read_after_block, Err(ZqlError::Semantic(SemanticError::UndeclaredVariable { .. }))));
+    }
+
+    #[test]
+    fn blocks_cannot_shadow_the_variables_around_them() {
+        let nested_let = execute("let x = 1; if true { let x = 2; }");
+        let nested_const = execute("let x = 1; while x < 2 { const x = 2; }");
+
+        assert!(matches!(nested_let, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+        assert!(matches!(nested_const, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+        assert!(execute("if true { let y = 1; } if true { let y = 2; }").is_ok());
+    }
+
+    #[test]
+    fn loops_declare_their_block_variables_afresh_each_iteration() {
+        let compiler = execute("let i = 0; while i < 3 { let step = 1; set i = i + step; }").unwrap();
+
+        assert_eq!(compiler.0.environment.get("i"), Some(&number(3)));
+    }
+
+    #[test]
+    fn assignment_updates_the_innermost_declaration() {
+        let mut environment = Environment::new();
+        environment.declare("x", number(1), false, span()).unwrap();
+        environment.push_scope(Scope::new());
+
+        environment.assign("x", number(2), span()).unwrap();
+        environment.pop_scope();
+
+        assert_eq!(environment.get("x"), Some(&number(2)));
+    }
+}
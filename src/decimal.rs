@@ -0,0 +1,468 @@
+//! Exact decimal arithmetic for ZQL numbers. Floating point values can't hold
+//! amounts like 0.1 exactly and may round differently between platforms, so all
+//! numbers are instead stored as a count of 10^-18 units in an `i128`.
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places every ZQL number is held to
+pub const SCALE: u32 = 18;
+
+/// The value of 1 at the fixed scale
+const ONE: i128 = 1_000_000_000_000_000_000;
+
+/// A fixed point decimal number with 18 decimal places
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Decimal(i128);
+
+/// How to round a result that has more decimal places than can be kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Towards zero, dropping any extra places
+    Down,
+    /// Away from zero whenever there are extra places
+    Up,
+    /// To the nearest value, with halves rounded away from zero
+    HalfUp,
+    /// To the nearest value, with halves rounded to the even neighbour
+    HalfEven
+}
+
+/// Ways a decimal calculation can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimalError {
+    /// The result is too large to be represented
+    Overflow,
+    /// The result is not zero, but too small to be represented
+    Underflow,
+    DivisionByZero,
+    /// The text being parsed is not a decimal number
+    InvalidFormat
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl Decimal {
+    /// Creates a decimal holding a whole number
+    ///
+    /// ### Arguments
+    ///
+    /// * `value`   - The whole number
+    pub fn from_int(value: i64) -> Decimal {
+        Decimal(value as i128 * ONE)
+    }
+
+    /// Gets zero
+    pub fn zero() -> Decimal {
+        Decimal(0)
+    }
+
+    /// Gets the number of 10^-18 units the value is made up of
+    pub fn units(&self) -> i128 {
+        self.0
+    }
+
+    /// Checks whether the value is exactly zero
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Checks whether the value is less than zero
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    /// Adds two decimals
+    ///
+    /// ### Arguments
+    ///
+    /// * `other`   - The value to add
+    pub fn checked_add(&self, other: Decimal) -> Result<Decimal, DecimalError> {
+        self.0.checked_add(other.0).map(Decimal).ok_or(DecimalError::Overflow)
+    }
+
+    /// Subtracts one decimal from another
+    ///
+    /// ### Arguments
+    ///
+    /// * `other`   - The value to subtract
+    pub fn checked_sub(&self, other: Decimal) -> Result<Decimal, DecimalError> {
+        self.0.checked_sub(other.0).map(Decimal).ok_or(DecimalError::Overflow)
+    }
+
+    /// Negates the decimal
+    pub fn checked_neg(&self) -> Result<Decimal, DecimalError> {
+        self.0.checked_neg().map(Decimal).ok_or(DecimalError::Overflow)
+    }
+
+    /// Multiplies two decimals, rounding any places beyond the fixed scale
+    ///
+    /// ### Arguments
+    ///
+    /// * `other`       - The value to multiply by
+    /// * `rounding`    - How to round places beyond the fixed scale
+    pub fn checked_mul(&self, other: Decimal, rounding: RoundingMode) -> Result<Decimal, DecimalError> {
+        let negative = self.is_negative() != other.is_negative();
+        let (a, b) = (self.0.unsigned_abs(), other.0.unsigned_abs());
+        let one = ONE as u128;
+
+        // Splitting both values into whole and fractional parts keeps every
+        // partial product within range unless the result itself overflows
+        let (a_whole, a_fraction) = (a / one, a % one);
+        let (b_whole, b_fraction) = (b / one, b % one);
+        let fraction_product = a_fraction * b_fraction;
+
+        let whole = a_whole
+            .checked_mul(b_whole)
+            .and_then(|p| p.checked_mul(one))
+            .and_then(|p| p.checked_add(a_whole.checked_mul(b_fraction)?))
+            .and_then(|p| p.checked_add(a_fraction.checked_mul(b_whole)?))
+            .and_then(|p| p.checked_add(fraction_product / one))
+            .ok_or(DecimalError::Overflow)?;
+
+        let result = Decimal::round_quotient(whole, fraction_product % one, one, negative, rounding)?;
+
+        if result.is_zero() && a != 0 && b != 0 {
+            return Err(DecimalError::Underflow);
+        }
+
+        Ok(result)
+    }
+
+    /// Divides one decimal by another, rounding any places beyond the fixed scale
+    ///
+    /// ### Arguments
+    ///
+    /// * `other`       - The value to divide by
+    /// * `rounding`    - How to round places beyond the fixed scale
+    pub fn checked_div(&self, other: Decimal, rounding: RoundingMode) -> Result<Decimal, DecimalError> {
+        if other.is_zero() {
+            return Err(DecimalError::DivisionByZero);
+        }
+
+        let negative = self.is_negative() != other.is_negative();
+        let (a, b) = (self.0.unsigned_abs(), other.0.unsigned_abs());
+
+        // Long division, one decimal place at a time, so the dividend never
+        // needs to be scaled up front
+        let mut quotient = a / b;
+        let mut remainder = a % b;
+
+        for _ in 0..SCALE {
+            let (digit, next_remainder) = Decimal::shift_remainder(remainder, b);
+
+            quotient = quotient
+                .checked_mul(10)
+                .and_then(|q| q.checked_add(digit))
+                .ok_or(DecimalError::Overflow)?;
+            remainder = next_remainder;
+        }
+
+        let result = Decimal::round_quotient(quotient, remainder, b, negative, rounding)?;
+
+        if result.is_zero() && a != 0 {
+            return Err(DecimalError::Underflow);
+        }
+
+        Ok(result)
+    }
+
+    /// Multiplies the remainder of a long division by ten and divides it again,
+    /// giving the next digit of the quotient and the new remainder. The remainder
+    /// is added ten times rather than multiplied, as ten times a remainder of a
+    /// divisor above `u128::MAX / 10` doesn't fit in a `u128`.
+    ///
+    /// ### Arguments
+    ///
+    /// * `remainder`   - Remainder of the division so far, less than the divisor
+    /// * `divisor`     - The value divided by, no greater than `2^127`
+    fn shift_remainder(remainder: u128, divisor: u128) -> (u128, u128) {
+        let mut digit = 0;
+        let mut shifted = 0;
+
+        for _ in 0..10 {
+            // Both values are below the divisor, so their sum can't overflow
+            shifted += remainder;
+
+            if shifted >= divisor {
+                shifted -= divisor;
+                digit += 1;
+            }
+        }
+
+        (digit, shifted)
+    }
+
+    /// Rounds the decimal to the given number of decimal places
+    ///
+    /// ### Arguments
+    ///
+    /// * `places`      - Number of decimal places to keep
+    /// * `rounding`    - How to round the dropped places
+    pub fn round_to(&self, places: u32, rounding: RoundingMode) -> Result<Decimal, DecimalError> {
+        if places >= SCALE {
+            return Ok(*self);
+        }
+
+        let unit = 10u128.pow(SCALE - places);
+        let value = self.0.unsigned_abs();
+        let rounded = Decimal::round_quotient(value / unit, value % unit, unit, self.is_negative(), rounding)?;
+
+        rounded.0
+            .unsigned_abs()
+            .checked_mul(unit)
+            .and_then(|v| i128::try_from(v).ok())
+            .map(|v| if self.is_negative() { Decimal(-v) } else { Decimal(v) })
+            .ok_or(DecimalError::Overflow)
+    }
+
+    /// Applies a rounding mode to the result of an unsigned division, then
+    /// restores the sign
+    ///
+    /// ### Arguments
+    ///
+    /// * `quotient`    - Whole part of the division
+    /// * `remainder`   - Remainder of the division
+    /// * `divisor`     - The value divided by
+    /// * `negative`    - Whether the true result is negative
+    /// * `rounding`    - How to round the remainder
+    fn round_quotient(
+        quotient: u128,
+        remainder: u128,
+        divisor: u128,
+        negative: bool,
+        rounding: RoundingMode
+    ) -> Result<Decimal, DecimalError> {
+        let round_away = remainder != 0 && match rounding {
+            RoundingMode::Down => false,
+            RoundingMode::Up => true,
+            RoundingMode::HalfUp => remainder >= divisor - remainder,
+            RoundingMode::HalfEven => match remainder.cmp(&(divisor - remainder)) {
+                Ordering::Greater => true,
+                Ordering::Less => false,
+                Ordering::Equal => quotient % 2 == 1
+            }
+        };
+
+        let magnitude = if round_away { quotient.checked_add(1) } else { Some(quotient) }
+            .and_then(|m| i128::try_from(m).ok())
+            .ok_or(DecimalError::Overflow)?;
+
+        Ok(if negative { Decimal(-magnitude) } else { Decimal(magnitude) })
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = DecimalError;
+
+    /// Parses a decimal written as digits with an optional sign and fractional
+    /// part, such as `10`, `-3` or `0.25`. Values with more than 18 decimal
+    /// places underflow rather than being rounded silently.
+    fn from_str(input: &str) -> Result<Decimal, DecimalError> {
+        let (negative, digits) = match input.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, input)
+        };
+
+        let (whole, fraction) = match digits.find('.') {
+            Some(i) => (&digits[..i], &digits[i + 1..]),
+            None => (digits, "")
+        };
+
+        let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+
+        if !is_digits(whole) || (digits.contains('.') && !is_digits(fraction)) {
+            return Err(DecimalError::InvalidFormat);
+        }
+
+        if fraction.len() > SCALE as usize {
+            return Err(DecimalError::Underflow);
+        }
+
+        // The sign is applied to the whole value at the end, so the fractional
+        // part always adds to the magnitude
+        let whole_value = whole.parse::<u128>().map_err(|_| DecimalError::Overflow)?;
+        let fraction_value = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<u128>().map_err(|_| DecimalError::Overflow)?
+                * 10u128.pow(SCALE - fraction.len() as u32)
+        };
+
+        let magnitude = whole_value
+            .checked_mul(ONE as u128)
+            .and_then(|w| w.checked_add(fraction_value))
+            .ok_or(DecimalError::Overflow)?;
+
+        if negative {
+            0i128.checked_sub_unsigned(magnitude).map(Decimal).ok_or(DecimalError::Overflow)
+        } else {
+            i128::try_from(magnitude).map(Decimal).map_err(|_| DecimalError::Overflow)
+        }
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let whole = value / ONE as u128;
+        let fraction = value % ONE as u128;
+
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, whole);
+        }
+
+        let places = format!("{:018}", fraction);
+        write!(f, "{}{}.{}", sign, whole, places.trim_end_matches('0'))
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decimal({})", self)
+    }
+}
+
+impl fmt::Display for DecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecimalError::Overflow => write!(f, "the result is too large to be represented"),
+            DecimalError::Underflow => write!(f, "the result is too small to be represented"),
+            DecimalError::DivisionByZero => write!(f, "division by zero"),
+            DecimalError::InvalidFormat => write!(f, "the value is not a decimal number")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decimal, DecimalError, RoundingMode};
+
+    fn decimal(input: &str) -> Decimal {
+        input.parse().unwrap()
+    }
+
+    fn divide(a: &str, b: &str, rounding: RoundingMode) -> Result<Decimal, DecimalError> {
+        decimal(a).checked_div(decimal(b), rounding)
+    }
+
+    fn round(input: &str, places: u32, rounding: RoundingMode) -> String {
+        decimal(input).round_to(places, rounding).unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_whole_and_fractional_numbers() {
+        assert_eq!(decimal("10"), Decimal::from_int(10));
+        assert_eq!(decimal("0.25").to_string(), "0.25");
+        assert_eq!(decimal("007.500").to_string(), "7.5");
+        assert_eq!(decimal("0.000000000000000001").to_string(), "0.000000000000000001");
+    }
+
+    #[test]
+    fn parses_negative_numbers() {
+        assert_eq!(decimal("-1.5").to_string(), "-1.5");
+        assert_eq!(decimal("-0.5").to_string(), "-0.5");
+        assert_eq!(decimal("-3"), Decimal::from_int(-3));
+        assert_eq!(decimal("-0"), Decimal::zero());
+        assert_eq!(decimal("-1.5").checked_add(decimal("1.5")), Ok(Decimal::zero()));
+    }
+
+    #[test]
+    fn rejects_malformed_numbers() {
+        for input in ["", "-", ".5", "1.", "1.2.3", "1.-5", "+1", "1e5", "--1", "1 0"] {
+            assert_eq!(input.parse::<Decimal>(), Err(DecimalError::InvalidFormat), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parsing_rejects_values_out_of_range() {
+        assert_eq!("0.0000000000000000001".parse::<Decimal>(), Err(DecimalError::Underflow));
+        assert_eq!("170141183460469231732".parse::<Decimal>(), Err(DecimalError::Overflow));
+        assert_eq!("-170141183460469231732".parse::<Decimal>(), Err(DecimalError::Overflow));
+        assert!("170141183460469231731.687303715884105727".parse::<Decimal>().is_ok());
+        assert!("-170141183460469231731.687303715884105728".parse::<Decimal>().is_ok());
+    }
+
+    #[test]
+    fn division_rounds_with_each_mode() {
+        assert_eq!(divide("2", "3", RoundingMode::Down).unwrap().to_string(), "0.666666666666666666");
+        assert_eq!(divide("2", "3", RoundingMode::Up).unwrap().to_string(), "0.666666666666666667");
+        assert_eq!(divide("2", "3", RoundingMode::HalfUp).unwrap().to_string(), "0.666666666666666667");
+        assert_eq!(divide("2", "3", RoundingMode::HalfEven).unwrap().to_string(), "0.666666666666666667");
+        assert_eq!(divide("-2", "3", RoundingMode::Down).unwrap().to_string(), "-0.666666666666666666");
+        assert_eq!(divide("-2", "3", RoundingMode::Up).unwrap().to_string(), "-0.666666666666666667");
+    }
+
+    #[test]
+    fn division_rounds_halves_by_mode() {
+        // Three units divided by two lies exactly halfway between one and two units
+        let three_halves = |rounding| decimal("0.000000000000000003").checked_div(Decimal::from_int(2), rounding);
+
+        assert_eq!(three_halves(RoundingMode::Down).unwrap().to_string(), "0.000000000000000001");
+        assert_eq!(three_halves(RoundingMode::Up).unwrap().to_string(), "0.000000000000000002");
+        assert_eq!(three_halves(RoundingMode::HalfUp).unwrap().to_string(), "0.000000000000000002");
+        assert_eq!(three_halves(RoundingMode::HalfEven).unwrap().to_string(), "0.000000000000000002");
+    }
+
+    #[test]
+    fn division_by_large_divisors_does_not_overflow() {
+        let quotient = divide("50000000000000000000", "60000000000000000000", RoundingMode::HalfEven);
+
+        assert_eq!(quotient.unwrap().to_string(), "0.833333333333333333");
+        assert_eq!(
+            divide("170141183460469231731", "170141183460469231731", RoundingMode::Down),
+            Ok(Decimal::from_int(1))
+        );
+    }
+
+    #[test]
+    fn round_to_applies_each_mode() {
+        assert_eq!(round("2.5", 0, RoundingMode::Down), "2");
+        assert_eq!(round("2.5", 0, RoundingMode::Up), "3");
+        assert_eq!(round("2.5", 0, RoundingMode::HalfUp), "3");
+        assert_eq!(round("2.5", 0, RoundingMode::HalfEven), "2");
+        assert_eq!(round("3.5", 0, RoundingMode::HalfEven), "4");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfUp), "-3");
+        assert_eq!(round("-2.5", 0, RoundingMode::HalfEven), "-2");
+        assert_eq!(round("-2.4", 0, RoundingMode::Up), "-3");
+        assert_eq!(round("1.23456789", 6, RoundingMode::HalfEven), "1.234568");
+        assert_eq!(round("1.2345", 18, RoundingMode::Up), "1.2345");
+    }
+
+    #[test]
+    fn multiplication_rounds_beyond_the_scale() {
+        let product = decimal("0.000000000000000001").checked_mul(decimal("0.5"), RoundingMode::HalfUp);
+
+        assert_eq!(product.unwrap().to_string(), "0.000000000000000001");
+        assert_eq!(decimal("1.5").checked_mul(decimal("-2"), RoundingMode::Down), Ok(Decimal::from_int(-3)));
+    }
+
+    #[test]
+    fn results_out_of_range_overflow() {
+        let max = decimal("170141183460469231731");
+
+        assert_eq!(max.checked_add(max), Err(DecimalError::Overflow));
+        assert_eq!(max.checked_mul(Decimal::from_int(2), RoundingMode::Down), Err(DecimalError::Overflow));
+        assert_eq!(max.checked_div(decimal("0.5"), RoundingMode::Down), Err(DecimalError::Overflow));
+        assert_eq!(decimal("170141183460469231731.6").round_to(0, RoundingMode::Up), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn results_too_small_underflow() {
+        let unit = decimal("0.000000000000000001");
+
+        assert_eq!(unit.checked_div(Decimal::from_int(2), RoundingMode::Down), Err(DecimalError::Underflow));
+        assert_eq!(unit.checked_div(Decimal::from_int(2), RoundingMode::HalfEven), Err(DecimalError::Underflow));
+        assert_eq!(unit.checked_mul(unit, RoundingMode::Down), Err(DecimalError::Underflow));
+        assert_eq!(unit.checked_div(Decimal::from_int(2), RoundingMode::HalfUp), Ok(unit));
+    }
+
+    #[test]
+    fn division_by_zero_fails() {
+        assert_eq!(divide("1", "0", RoundingMode::Down), Err(DecimalError::DivisionByZero));
+    }
+}
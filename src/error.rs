@@ -0,0 +1,334 @@
+use std::error::Error;
+use std::fmt;
+use crate::address::AddressError;
+use crate::decimal::DecimalError;
+use crate::ledger::LedgerError;
+use crate::lexer::Span;
+
+/// Top level error for anything that can go wrong while running a ZQL script.
+/// Each stage of execution has its own error type, and every error carries the
+/// span of the script that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZqlError {
+    Lex(LexError),
+    Parse(ParseError),
+    Semantic(SemanticError),
+    Runtime(RuntimeError)
+}
+
+/// Errors raised while splitting a script into lexical tokens
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnexpectedCharacter { character: char, span: Span },
+    InvalidNumber { literal: String, span: Span },
+    UnknownOperator { operator: String, span: Span },
+    UnterminatedString { span: Span },
+    InvalidEscape { sequence: String, span: Span },
+    InvalidAddress { literal: String, error: AddressError, span: Span }
+}
+
+/// Errors raised while building a syntax tree from lexical tokens
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken { found: String, span: Span },
+    UnexpectedEnd { expected: String, span: Span },
+    Expected { expected: String, found: String, span: Span },
+    StackKeywordInHeap { keyword: String, span: Span },
+    HeapKeywordInStack { keyword: String, span: Span },
+    NestingTooDeep { limit: usize, span: Span }
+}
+
+/// Errors raised when a syntax tree is well formed but cannot be given a meaning
+#[derive(Debug, Clone, PartialEq)]
+pub enum SemanticError {
+    UnsupportedExpression { found: String, span: Span },
+    UndeclaredVariable { name: String, span: Span },
+    ConstantReassignment { name: String, span: Span },
+    AlreadyDeclared { name: String, span: Span },
+    InvalidStackStatement { expected: String, span: Span }
+}
+
+/// Errors raised while executing a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UndefinedVariable { name: String, span: Span },
+    TypeMismatch { expected: String, found: String, span: Span },
+    InvalidOperator { operator: String, span: Span },
+    ConstantReassignment { name: String, span: Span },
+    AlreadyDeclared { name: String, span: Span },
+    DivisionByZero { span: Span },
+    MissingPayer { span: Span },
+    Overflow { span: Span },
+    Underflow { span: Span },
+    OutOfFuel { limit: u64, span: Span },
+    NestingTooDeep { limit: usize, span: Span },
+    InvalidAmount { amount: String, span: Span },
+    InvalidEncoding { encoding: String, span: Span },
+    MintNotPermitted { asset: String, span: Span },
+    LedgerRejected { error: LedgerError, span: Span }
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl ZqlError {
+    /// Gets the span of the script that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            ZqlError::Lex(e) => e.span(),
+            ZqlError::Parse(e) => e.span(),
+            ZqlError::Semantic(e) => e.span(),
+            ZqlError::Runtime(e) => e.span()
+        }
+    }
+}
+
+impl LexError {
+    /// Gets the span of the script that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedCharacter { span, .. } |
+            LexError::InvalidNumber { span, .. } |
+            LexError::UnknownOperator { span, .. } |
+            LexError::UnterminatedString { span } |
+            LexError::InvalidEscape { span, .. } |
+            LexError::InvalidAddress { span, .. } => *span
+        }
+    }
+}
+
+impl ParseError {
+    /// Gets the span of the script that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. } |
+            ParseError::UnexpectedEnd { span, .. } |
+            ParseError::Expected { span, .. } |
+            ParseError::StackKeywordInHeap { span, .. } |
+            ParseError::HeapKeywordInStack { span, .. } |
+            ParseError::NestingTooDeep { span, .. } => *span
+        }
+    }
+}
+
+impl SemanticError {
+    /// Gets the span of the script that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            SemanticError::UnsupportedExpression { span, .. } |
+            SemanticError::UndeclaredVariable { span, .. } |
+            SemanticError::ConstantReassignment { span, .. } |
+            SemanticError::AlreadyDeclared { span, .. } |
+            SemanticError::InvalidStackStatement { span, .. } => *span
+        }
+    }
+}
+
+impl RuntimeError {
+    /// Gets the span of the script that caused the error
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::UndefinedVariable { span, .. } |
+            RuntimeError::TypeMismatch { span, .. } |
+            RuntimeError::InvalidOperator { span, .. } |
+            RuntimeError::ConstantReassignment { span, .. } |
+            RuntimeError::AlreadyDeclared { span, .. } |
+            RuntimeError::DivisionByZero { span } |
+            RuntimeError::MissingPayer { span } |
+            RuntimeError::Overflow { span } |
+            RuntimeError::Underflow { span } |
+            RuntimeError::OutOfFuel { span, .. } |
+            RuntimeError::NestingTooDeep { span, .. } |
+            RuntimeError::InvalidAmount { span, .. } |
+            RuntimeError::InvalidEncoding { span, .. } |
+            RuntimeError::MintNotPermitted { span, .. } |
+            RuntimeError::LedgerRejected { span, .. } => *span
+        }
+    }
+}
+
+impl RuntimeError {
+    /// Converts a failed decimal calculation into a runtime error
+    ///
+    /// ### Arguments
+    ///
+    /// * `error`   - The decimal error to convert
+    /// * `span`    - Location of the calculation in the script
+    pub fn from_decimal_error(error: DecimalError, span: Span) -> RuntimeError {
+        match error {
+            DecimalError::Overflow => RuntimeError::Overflow { span },
+            DecimalError::Underflow => RuntimeError::Underflow { span },
+            DecimalError::DivisionByZero => RuntimeError::DivisionByZero { span },
+            DecimalError::InvalidFormat => RuntimeError::TypeMismatch {
+                expected: String::from("a number"),
+                found: String::from("text that is not a number"),
+                span
+            }
+        }
+    }
+}
+
+impl fmt::Display for ZqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZqlError::Lex(e) => write!(f, "Lex error at {}: {}", e.span(), e),
+            ZqlError::Parse(e) => write!(f, "Parse error at {}: {}", e.span(), e),
+            ZqlError::Semantic(e) => write!(f, "Semantic error at {}: {}", e.span(), e),
+            ZqlError::Runtime(e) => write!(f, "Runtime error at {}: {}", e.span(), e)
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter { character, .. } => {
+                write!(f, "the character {:?} is not valid in ZQL", character)
+            }
+            LexError::InvalidNumber { literal, .. } => {
+                write!(f, "'{}' is not a valid number", literal)
+            }
+            LexError::UnknownOperator { operator, .. } => {
+                write!(f, "unknown operator '{}'", operator)
+            }
+            LexError::UnterminatedString { .. } => {
+                write!(f, "string literal is never closed")
+            }
+            LexError::InvalidEscape { sequence, .. } => {
+                write!(f, "'{}' is not a valid escape sequence", sequence)
+            }
+            LexError::InvalidAddress { literal, error, .. } => {
+                write!(f, "'@{}' is not a valid address: {}", literal, error)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken { found, .. } => {
+                write!(f, "the command or value {} is syntactically invalid", found)
+            }
+            ParseError::UnexpectedEnd { expected, .. } => {
+                write!(f, "expected {}, but the script ended", expected)
+            }
+            ParseError::Expected { expected, found, .. } => {
+                write!(f, "expected {}, but found {}", expected, found)
+            }
+            ParseError::StackKeywordInHeap { keyword, .. } => {
+                write!(f, "{} is a Stack keyword and needs to be declared in a Stack", keyword)
+            }
+            ParseError::HeapKeywordInStack { keyword, .. } => {
+                write!(f, "{} is a Heap keyword, but is being used in a ZQL Stack", keyword)
+            }
+            ParseError::NestingTooDeep { limit, .. } => {
+                write!(f, "this is nested more than {} levels deep", limit)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SemanticError::UnsupportedExpression { found, .. } => {
+                write!(f, "unknown or unsupported expression {}", found)
+            }
+            SemanticError::UndeclaredVariable { name, .. } => {
+                write!(f, "variable '{}' is used before it is declared with 'let', or outside the block declaring it", name)
+            }
+            SemanticError::ConstantReassignment { name, .. } => {
+                write!(f, "'{}' is a constant and cannot be assigned to", name)
+            }
+            SemanticError::AlreadyDeclared { name, .. } => {
+                write!(f, "'{}' is already declared, so cannot be declared again", name)
+            }
+            SemanticError::InvalidStackStatement { expected, .. } => {
+                write!(f, "invalid Stack statement, expected {}", expected)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::UndefinedVariable { name, .. } => {
+                write!(f, "variable '{}' is not declared", name)
+            }
+            RuntimeError::ConstantReassignment { name, .. } => {
+                write!(f, "'{}' is a constant and cannot be assigned to", name)
+            }
+            RuntimeError::AlreadyDeclared { name, .. } => {
+                write!(f, "'{}' is already declared, so cannot be declared again", name)
+            }
+            RuntimeError::TypeMismatch { expected, found, .. } => {
+                write!(f, "expected {}, but found {}", expected, found)
+            }
+            RuntimeError::InvalidOperator { operator, .. } => {
+                write!(f, "the operator {} cannot be used here", operator)
+            }
+            RuntimeError::DivisionByZero { .. } => {
+                write!(f, "division by zero")
+            }
+            RuntimeError::MissingPayer { .. } => {
+                write!(f, "no payer address was provided by the host for this transaction")
+            }
+            RuntimeError::InvalidAmount { amount, .. } => {
+                write!(f, "{} is not a valid amount, amounts must be greater than zero", amount)
+            }
+            RuntimeError::InvalidEncoding { encoding, .. } => {
+                write!(f, "the content is not valid {}", encoding)
+            }
+            RuntimeError::MintNotPermitted { asset, .. } => {
+                write!(f, "the host has not permitted this script to mint {}", asset)
+            }
+            RuntimeError::LedgerRejected { error, .. } => {
+                write!(f, "the ledger refused the transaction: {}", error)
+            }
+            RuntimeError::OutOfFuel { limit, .. } => {
+                write!(f, "the script ran out of fuel, having been given {}", limit)
+            }
+            RuntimeError::NestingTooDeep { limit, .. } => {
+                write!(f, "this is nested more than {} levels deep", limit)
+            }
+            RuntimeError::Overflow { .. } => {
+                write!(f, "the result is too large to be represented")
+            }
+            RuntimeError::Underflow { .. } => {
+                write!(f, "the result is not zero, but too small to be represented")
+            }
+        }
+    }
+}
+
+impl Error for ZqlError {}
+impl Error for LexError {}
+impl Error for ParseError {}
+impl Error for SemanticError {}
+impl Error for RuntimeError {}
+
+impl From<LexError> for ZqlError {
+    fn from(e: LexError) -> ZqlError {
+        ZqlError::Lex(e)
+    }
+}
+
+impl From<ParseError> for ZqlError {
+    fn from(e: ParseError) -> ZqlError {
+        ZqlError::Parse(e)
+    }
+}
+
+impl From<SemanticError> for ZqlError {
+    fn from(e: SemanticError) -> ZqlError {
+        ZqlError::Semantic(e)
+    }
+}
+
+impl From<RuntimeError> for ZqlError {
+    fn from(e: RuntimeError) -> ZqlError {
+        ZqlError::Runtime(e)
+    }
+}
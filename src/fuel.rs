@@ -0,0 +1,46 @@
+//! Fuel meters the work a script does. Every operation the compiler performs
+//! costs a fixed amount of fuel from the table below, and execution fails once
+//! the fuel supplied for it has run out. The same script therefore always uses
+//! the same fuel and fails at the same point, on whichever node runs it.
+
+/// Fuel supplied for each execution when the host doesn't supply a limit of its own
+pub const DEFAULT_FUEL_LIMIT: u64 = 100_000;
+
+/// Definitions of the operations that cost fuel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Running a single Heap or Stack statement
+    Statement,
+    /// Running a single iteration of a loop
+    Iteration,
+    /// Reading the value of a variable
+    VariableLookup,
+    /// Applying an arithmetic operator
+    Arithmetic,
+    /// Applying a comparison or logical operator
+    Comparison,
+    /// Adding a payment, mint or data operation to a transaction
+    StackEmission,
+    /// Deriving a new address with NEW ADDRESS
+    AddressCreation,
+    /// Reading the balance or history of an address from the ledger
+    LedgerQuery
+}
+
+/// Get the fuel cost of an operation
+///
+/// ### Arguments
+///
+/// * `operation`   - The operation to get the cost of
+pub fn get_fuel_cost(operation: Operation) -> u64 {
+    match operation {
+        Operation::Statement =>         { 1 }
+        Operation::Iteration =>         { 1 }
+        Operation::VariableLookup =>    { 1 }
+        Operation::Arithmetic =>        { 2 }
+        Operation::Comparison =>        { 1 }
+        Operation::StackEmission =>     { 20 }
+        Operation::AddressCreation =>   { 20 }
+        Operation::LedgerQuery =>       { 50 }
+    }
+}
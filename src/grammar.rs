@@ -1,17 +1,14 @@
-use crate::syntax::ParseNode;
+use crate::error::LexError;
+use crate::lexer::Span;
 
-/// Definitions of grammar items
-#[derive(Debug, Clone, PartialEq)]
-pub enum GrammarAtom {
-    Number(f64),
-    Value(String),
-    Punc(char),
-    Op(OpAtom),
-    HeapExpression,
-    StackExpression,
-    StackKeyword(StackKeyword),
-    HeapKeyword(HeapKeyword)
-}
+/// Variables provided to every script by the host rather than declared with SET.
+/// Scripts can read them but not assign to them.
+pub const BUILTIN_VARIABLES: [&str; 1] = ["block_height"];
+
+/// Deepest that expressions and blocks may be nested. Parsing, checking and
+/// executing a script each recurse once per level of nesting, so this keeps
+/// any script from exhausting the stack.
+pub const MAX_NESTING_DEPTH: usize = 64;
 
 /// Definitions of operation types
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,7 +20,13 @@ pub enum OpAtom {
     Divide,
     LessThan,
     GreaterThan,
-    EqualTo
+    LessThanOrEqualTo,
+    GreaterThanOrEqualTo,
+    EqualTo,
+    NotEqualTo,
+    And,
+    Or,
+    Not
 }
 
 /// Definitions of stack keywords
@@ -44,7 +47,8 @@ pub enum StackKeyword {
     Amount,
     Encoding,
     In,
-    Address
+    Address,
+    To
 }
 
 /// Definitions of heap keywords
@@ -57,7 +61,9 @@ pub enum HeapKeyword {
     For,
     In,
     Stack,
-    Set
+    Set,
+    Let,
+    Const
 }
 
 /// Get the heap keyword enum for the provided keyword.
@@ -76,6 +82,8 @@ pub fn get_heap_keyword(keyword: &str) -> Option<HeapKeyword> {
         "in" =>     { Some(HeapKeyword::In) }
         "stack" =>  { Some(HeapKeyword::Stack) }
         "set" =>    { Some(HeapKeyword::Set) }
+        "let" =>    { Some(HeapKeyword::Let) }
+        "const" =>  { Some(HeapKeyword::Const) }
         _ =>        { None }
     }
 }
@@ -103,48 +111,74 @@ pub fn get_stack_keyword(keyword: &str) -> Option<StackKeyword> {
         "AMOUNT" =>   { Some(StackKeyword::Amount) }
         "ENCODING" => { Some(StackKeyword::Encoding) }
         "IN" =>       { Some(StackKeyword::In) }
+        "ADDRESS" =>  { Some(StackKeyword::Address) }
+        "TO" =>       { Some(StackKeyword::To) }
         _ =>          { None }
     }
 }
 
-/// Performs a match against an operator to find a 
-/// grammar atom for it. This is a utility function for the parser.
-/// 
+/// Get the binding precedence of a binary operator. A higher value binds
+/// more tightly, so `*` and `/` are grouped before `+` and `-`, and `and`
+/// is grouped before `or`. Operators that cannot appear between two operands
+/// return `None`.
+///
 /// ### Arguments
-/// 
-/// * `operator`    - Operator to find a grammar atom for
-pub fn find_op_grammar_atom(operator: &String) -> ParseNode {
-    let mut node = ParseNode::new();
+///
+/// * `operator`    - Operator to find the precedence for
+pub fn get_op_precedence(operator: &OpAtom) -> Option<u8> {
+    match operator {
+        OpAtom::Or =>                                   { Some(1) }
+        OpAtom::And =>                                  { Some(2) }
+        OpAtom::EqualTo | OpAtom::NotEqualTo =>         { Some(3) }
+        OpAtom::LessThan
+        | OpAtom::GreaterThan
+        | OpAtom::LessThanOrEqualTo
+        | OpAtom::GreaterThanOrEqualTo =>               { Some(4) }
+        OpAtom::Add | OpAtom::Subtract =>               { Some(5) }
+        OpAtom::Multiply | OpAtom::Divide =>            { Some(6) }
+        OpAtom::To | OpAtom::Not =>                     { None }
+    }
+}
 
-    match operator.as_str() {
-        "*" => {
-            node.entry = GrammarAtom::Op(OpAtom::Multiply);
-        }
-        "+" => {
-            node.entry = GrammarAtom::Op(OpAtom::Add);
-        }
-        "-" => {
-            node.entry = GrammarAtom::Op(OpAtom::Subtract);
-        }
-        "/" => {
-            node.entry = GrammarAtom::Op(OpAtom::Divide);
-        }
-        "<" => {
-            node.entry = GrammarAtom::Op(OpAtom::LessThan);
-        }
-        ">" => {
-            node.entry = GrammarAtom::Op(OpAtom::GreaterThan);
-        }
-        "=" => {
-            node.entry = GrammarAtom::Op(OpAtom::To);
-        }
-        "==" => {
-            node.entry = GrammarAtom::Op(OpAtom::EqualTo);
-        }
-        _ => {
-            panic!("Unknown operator found: {}", operator);
-        }
+/// Get the precedence an operand of `not` is parsed at. Comparisons bind
+/// more tightly than `not`, so `not a == b` negates the whole comparison,
+/// while `and` and `or` bind less tightly.
+pub fn get_not_operand_precedence() -> u8 {
+    3
+}
+
+/// Get the operator enum for the provided operator.
+///
+/// ### Arguments
+///
+/// * `operator`    - The operator to convert to enum format
+pub fn get_op_atom(operator: &str) -> Option<OpAtom> {
+    match operator {
+        "*" =>  { Some(OpAtom::Multiply) }
+        "+" =>  { Some(OpAtom::Add) }
+        "-" =>  { Some(OpAtom::Subtract) }
+        "/" =>  { Some(OpAtom::Divide) }
+        "<" =>  { Some(OpAtom::LessThan) }
+        ">" =>  { Some(OpAtom::GreaterThan) }
+        "=" =>  { Some(OpAtom::To) }
+        "<=" => { Some(OpAtom::LessThanOrEqualTo) }
+        ">=" => { Some(OpAtom::GreaterThanOrEqualTo) }
+        "==" => { Some(OpAtom::EqualTo) }
+        "!=" => { Some(OpAtom::NotEqualTo) }
+        "and" =>    { Some(OpAtom::And) }
+        "or" =>     { Some(OpAtom::Or) }
+        "not" =>    { Some(OpAtom::Not) }
+        _ =>    { None }
     }
+}
 
-    return node;
-}
\ No newline at end of file
+/// Performs a match against an operator to find its enum, failing
+/// for unknown operators. This is a utility function for the parser.
+/// 
+/// ### Arguments
+/// 
+/// * `operator`    - Operator to find the enum for
+/// * `span`        - Location of the operator in the script
+pub fn find_op_atom(operator: &str, span: Span) -> Result<OpAtom, LexError> {
+    get_op_atom(operator).ok_or_else(|| LexError::UnknownOperator { operator: operator.to_string(), span })
+}
@@ -1,10 +1,1032 @@
-/// ZQL is required to simulate a kernel-like structure in order to execute its code
+//! ZQL is required to simulate a kernel-like structure in order to execute its code
+//!
+//! Execution is deterministic: the same script run against the same ledger by a
+//! kernel in the same state always produces the same variables, transactions,
+//! ledger changes and fuel used, or fails with the same error. Numbers are fixed
+//! point decimals rather than floats, variables and ledger state are held in
+//! ordered maps, and the only outside inputs a script can observe are the ledger,
+//! including its block height, and the payer and permissions set on the kernel.
+//! Nothing reads the clock or a source of randomness.
 
-use std::collections::HashMap;
-use crate::compiler::Compiler;
+use std::mem;
+use crate::address::Address;
+use crate::canonical::Canonical;
+use crate::compiler::{Compiler, ExecutionModel, Scope};
+use crate::decimal::RoundingMode;
+use crate::error::{RuntimeError, ZqlError};
+use crate::ledger::{Ledger, LedgerDiff, LedgerView};
+use crate::syntax::Parser;
+use crate::transaction::{Asset, TransactionIntent};
 
 
-/// Kernel instance
+/// Kernel instance, owning the ledger that scripts read and that the
+/// transactions they produce are applied to
 #[derive(Debug, Clone)]
-pub struct Kernel(pub Compiler);
+pub struct Kernel<L: Ledger> {
+    compiler: Compiler,
+    ledger: L,
+    /// Checkpoints currently open, innermost last
+    checkpoints: Vec<Snapshot<L>>
+}
 
+/// A saved copy of the execution state of a kernel and of its ledger
+#[derive(Debug, Clone)]
+pub struct Snapshot<L> {
+    model: ExecutionModel,
+    ledger: L
+}
+
+/// The result of a successful execution
+#[derive(Debug, Clone)]
+pub struct Execution {
+    /// The transactions produced and applied to the ledger
+    pub transactions: Vec<TransactionIntent>,
+    /// The fuel the execution used
+    pub fuel_used: u64
+}
+
+/// What a script would do if it were executed, as found by a simulation
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    /// The transactions the script would produce
+    pub transactions: Vec<TransactionIntent>,
+    /// How the ledger would change once the transactions were applied
+    pub diff: LedgerDiff,
+    /// The fuel the script would use
+    pub fuel_used: u64
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl<L: Ledger + Clone> Kernel<L> {
+
+    /// Creates a new Kernel instance
+    ///
+    /// ### Arguments
+    ///
+    /// * `ledger`      - The ledger scripts are run against
+    /// * `fuel_limit`  - Fuel each execution may use before it is halted
+    pub fn new(ledger: L, fuel_limit: u64) -> Kernel<L> {
+        let mut compiler = Compiler::new();
+        compiler.0.fuel_limit = fuel_limit;
+
+        Kernel {
+            compiler,
+            ledger,
+            checkpoints: Vec::new()
+        }
+    }
+
+    /// Gets the ledger scripts are run against
+    pub fn ledger(&self) -> &L {
+        &self.ledger
+    }
+
+    /// Gets the ledger scripts are run against so the host can change it, such
+    /// as when a new block is added
+    pub fn ledger_mut(&mut self) -> &mut L {
+        &mut self.ledger
+    }
+
+    /// Gets the global variables declared by the scripts run so far, along with
+    /// those provided by the kernel
+    pub fn globals(&self) -> &Scope {
+        self.compiler.0.environment.globals()
+    }
+
+    /// Sets the address scripts pay from and act on behalf of
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`   - The address of the payer
+    pub fn set_payer(&mut self, payer: Address) {
+        self.compiler.0.payer = Some(payer);
+    }
+
+    /// Sets how results are rounded when they have more decimal places than
+    /// can be kept, both in arithmetic and when amounts are rounded to the
+    /// precision of their asset
+    ///
+    /// ### Arguments
+    ///
+    /// * `rounding`    - The rounding mode to use
+    pub fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.compiler.0.rounding = rounding;
+    }
+
+    /// Sets the assets scripts are permitted to mint with NEW
+    ///
+    /// ### Arguments
+    ///
+    /// * `assets`  - The assets that may be minted
+    pub fn set_mintable_assets(&mut self, assets: Vec<Asset>) {
+        self.compiler.0.mintable_assets = assets;
+    }
+
+    /// Parses and executes a script, then evaluates any triggers in case their
+    /// conditions already hold. The transactions produced are applied to the
+    /// ledger in order and returned along with the fuel used. Triggers are
+    /// evaluated before those transactions are applied, so they only see them
+    /// once `notify_state_change` is next called. If the script
+    /// fails at any point, including when the ledger refuses one of its
+    /// transactions, the kernel and its ledger are rolled back to how they
+    /// were before the script ran.
+    ///
+    /// ### Arguments
+    ///
+    /// * `script`  - The script to execute
+    pub fn execute(&mut self, script: &str) -> Result<Execution, ZqlError> {
+        let globals: Vec<(String, bool)> = self.globals()
+            .iter()
+            .map(|(name, variable)| (name.clone(), variable.constant))
+            .collect();
+        let syntax_tree = Parser.parse_script_with_globals(script, &globals)?;
+
+        self.run_execution(|kernel| {
+            kernel.compiler.set_block_height(kernel.ledger.block_height())?;
+            kernel.compiler.execute_block(&syntax_tree, &kernel.ledger)?;
+            kernel.compiler.evaluate_triggers(&kernel.ledger)
+        })
+    }
+
+    /// Executes a script as `execute` would, but against a view of a snapshot of
+    /// the ledger, and reports what the script would do. Neither the snapshot,
+    /// the kernel's own ledger nor the state of the kernel is changed, so any
+    /// variables or triggers the script declares are discarded afterwards.
+    ///
+    /// ### Arguments
+    ///
+    /// * `script`      - The script to simulate
+    /// * `snapshot`    - State of the ledger to simulate the script against
+    pub fn simulate(&self, script: &str, snapshot: &L) -> Result<Simulation, ZqlError> {
+        let mut kernel = Kernel {
+            compiler: self.compiler.clone(),
+            ledger: LedgerView::new(snapshot),
+            checkpoints: Vec::new()
+        };
+        let execution = kernel.execute(script)?;
+
+        Ok(Simulation {
+            diff: LedgerDiff::between(snapshot, &kernel.ledger, &execution.transactions),
+            transactions: execution.transactions,
+            fuel_used: execution.fuel_used
+        })
+    }
+
+    /// Evaluates every pending trigger after the state of the ledger has
+    /// changed, such as when a new block has been added. The transactions
+    /// produced by any triggers that fired are applied to the ledger and
+    /// returned along with the fuel used. If a trigger fails, the kernel and
+    /// its ledger are rolled back to how they were before the triggers were
+    /// evaluated.
+    pub fn notify_state_change(&mut self) -> Result<Execution, ZqlError> {
+        self.run_execution(|kernel| {
+            kernel.compiler.set_block_height(kernel.ledger.block_height())?;
+            kernel.compiler.evaluate_triggers(&kernel.ledger)
+        })
+    }
+
+    /// Takes a snapshot of the execution state of the kernel and of its ledger
+    pub fn snapshot(&self) -> Snapshot<L> {
+        Snapshot {
+            model: self.compiler.0.clone(),
+            ledger: self.ledger.clone()
+        }
+    }
+
+    /// Restores the execution state of the kernel and its ledger from a snapshot.
+    /// Any checkpoints that are open are left open.
+    ///
+    /// ### Arguments
+    ///
+    /// * `snapshot`    - The snapshot to restore
+    pub fn restore(&mut self, snapshot: Snapshot<L>) {
+        self.compiler.0 = snapshot.model;
+        self.ledger = snapshot.ledger;
+    }
+
+    /// Opens a checkpoint, which can later be rolled back to or committed.
+    /// Checkpoints nest, so rolling back or committing always applies to the
+    /// checkpoint opened most recently. Executions don't need one, as a
+    /// failed execution is always undone and the ledger applies the
+    /// transactions of an execution all together or not at all.
+    pub fn checkpoint(&mut self) {
+        let snapshot = self.snapshot();
+        self.checkpoints.push(snapshot);
+    }
+
+    /// Rolls the kernel and its ledger back to the most recent checkpoint and
+    /// closes it. Returns false if there is no checkpoint open.
+    pub fn rollback(&mut self) -> bool {
+        match self.checkpoints.pop() {
+            Some(snapshot) => {
+                self.restore(snapshot);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Closes the most recent checkpoint, keeping every change made since it
+    /// was opened. Returns false if there is no checkpoint open.
+    pub fn commit(&mut self) -> bool {
+        self.checkpoints.pop().is_some()
+    }
+
+    /// Gets the fuel used by the most recent execution, including one that failed
+    pub fn fuel_used(&self) -> u64 {
+        self.compiler.0.fuel_used
+    }
+
+    /// Gets the number of checkpoints currently open
+    pub fn checkpoint_depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Runs an execution with a fresh supply of fuel, then applies the
+    /// transactions it produced. The ledger is only changed once the
+    /// execution has succeeded, so if it fails only the execution state of
+    /// the kernel needs to be restored. The fuel used is kept either way, as
+    /// the work was done even if its effects are undone.
+    ///
+    /// ### Arguments
+    ///
+    /// * `operation`   - The execution to run
+    fn run_execution(
+        &mut self,
+        operation: impl FnOnce(&mut Kernel<L>) -> Result<(), ZqlError>
+    ) -> Result<Execution, ZqlError> {
+        let model = self.compiler.0.clone();
+        self.compiler.0.fuel_used = 0;
+
+        match operation(self).and_then(|_| self.commit_transactions()) {
+            Ok(transactions) => Ok(Execution {
+                transactions,
+                fuel_used: self.compiler.0.fuel_used
+            }),
+            Err(e) => {
+                let fuel_used = self.compiler.0.fuel_used;
+                self.compiler.0 = model;
+                self.compiler.0.fuel_used = fuel_used;
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies the transactions produced so far to the ledger in order, handing
+    /// them over to the caller once every one has been applied
+    fn commit_transactions(&mut self) -> Result<Vec<TransactionIntent>, ZqlError> {
+        let transactions = mem::take(&mut self.compiler.0.transactions);
+
+        self.ledger.apply_all(&transactions).map_err(|(index, error)| RuntimeError::LedgerRejected {
+            error,
+            span: transactions[index].span
+        })?;
+
+        Ok(transactions)
+    }
+}
+
+impl<L: Ledger + Canonical> Canonical for Kernel<L> {
+    /// The kernel is encoded with the state a later execution can observe.
+    /// Checkpoints and the fuel used by the last execution are left out.
+    fn encode(&self, out: &mut Vec<u8>) {
+        let model = &self.compiler.0;
+
+        model.environment.globals().encode(out);
+        model.triggers.encode(out);
+        model.payer.encode(out);
+        model.mintable_assets.encode(out);
+        model.rounding.encode(out);
+        model.fuel_limit.encode(out);
+        model.block_height.encode(out);
+        self.ledger.encode(out);
+    }
+}
+
+impl Canonical for Execution {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.transactions.encode(out);
+        self.fuel_used.encode(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use crate::canonical::{canonical_bytes, canonical_hash};
+    use crate::address::{Address, ADDRESS_LENGTH};
+    use crate::compiler::AssignmentValue;
+    use crate::decimal::{Decimal, RoundingMode};
+    use crate::error::{RuntimeError, SemanticError, ZqlError};
+    use crate::fuel::DEFAULT_FUEL_LIMIT;
+    use crate::ledger::{InMemoryLedger, Ledger};
+    use crate::transaction::{Asset, DataEncoding, DataOperation, TransactionOperation, Transfer};
+    use super::{Execution, Kernel};
+
+    /// Number of times each script is run when checking it always gives the same result
+    const RUNS: usize = 20;
+
+    /// A script using every kind of statement, so that any source of
+    /// nondeterminism in the interpreter shows up in its result
+    const FULL_SCRIPT: &str = "
+        const friend = FRIEND;
+        const payer = PAYER;
+        let total = 0;
+
+        for amount in [1, 2.5, 10 / 3] {
+            set total = total + amount;
+        }
+
+        let i = 0;
+
+        while i < 3 {
+            set i = i + 1;
+        }
+
+        stack [ balance = GET AMOUNT ZNT WHERE WHO = friend ]
+        stack [ vault = NEW ADDRESS ]
+
+        if total > 5 and balance < 100 {
+            stack [ PAY friend total ZNT AND PAY vault 1 SDL ]
+        } else {
+            stack [ PAY friend 1 ZNT ]
+        }
+
+        stack [ NEW ZNT 7 TO vault ]
+        stack [ TRANSACT [ CREATE 'profile' '{\"total\": 1}' ENCODING json; PAY vault 2 ZNT ] ]
+        stack [ UPDATE 'profile' 'cafe' ENCODING hex WHERE WHO = payer ]
+        stack [ history = GET TRANSACT WHERE WHO = vault ]
+
+        when block_height > 10 {
+            stack [ PAY friend i ZNT ]
+        }
+    ";
+
+    fn address(seed: u8) -> Address {
+        Address::derive(&Address::from_bytes([seed; ADDRESS_LENGTH]), 0)
+    }
+
+    fn payer() -> Address {
+        address(1)
+    }
+
+    fn friend() -> Address {
+        address(2)
+    }
+
+    /// Fills in the addresses used by a script
+    fn script(source: &str) -> String {
+        source
+            .replace("FRIEND", &friend().to_string())
+            .replace("PAYER", &payer().to_string())
+    }
+
+    /// Creates a kernel whose payer holds funds of every asset and may mint ZNT
+    fn kernel() -> Kernel<InMemoryLedger> {
+        let mut ledger = InMemoryLedger::new();
+        ledger.add_output(friend(), Asset::Znt, Decimal::from_int(40)).unwrap();
+
+        for asset in [Asset::Znt, Asset::Sdl] {
+            ledger.add_output(payer(), asset, Decimal::from_int(100)).unwrap();
+        }
+
+        let mut kernel = Kernel::new(ledger, DEFAULT_FUEL_LIMIT);
+        kernel.set_payer(payer());
+        kernel.set_mintable_assets(vec![Asset::Znt]);
+
+        kernel
+    }
+
+    /// Runs a script on a fresh kernel, then moves the chain on to a new block.
+    /// Gives the hash of the canonical encoding of the result of each step
+    /// together with the state of the kernel and its ledger afterwards.
+    fn run(source: &str) -> String {
+        let mut kernel = kernel();
+        let result = kernel.execute(&script(source));
+        let mut hash = canonical_hash(&(&result, &kernel)).to_vec();
+
+        kernel.ledger_mut().set_block_height(20);
+        let result = kernel.notify_state_change();
+        hash.extend(canonical_hash(&(&result, &kernel)));
+
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Checks that running a script repeatedly always gives the hashes pinned for it
+    ///
+    /// ### Arguments
+    ///
+    /// * `source`      - The script to run
+    /// * `expected`    - Hex of the hashes the script must always give
+    fn assert_deterministic(source: &str, expected: &str) {
+        for _ in 0..RUNS {
+            assert_eq!(run(source), expected);
+        }
+    }
+
+    #[test]
+    fn full_script_is_deterministic() {
+        assert_deterministic(
+            FULL_SCRIPT,
+            concat!(
+                "90133f1af3e105a781e6b389c82d9223e37b52622c67cab4f38573d33ee5fbce",
+                "a006e5c9b89e7dbb06e1a00f4986c2008233b42ff097c43edc7c47420227aca2"
+            )
+        );
+    }
+
+    #[test]
+    fn failing_scripts_are_deterministic() {
+        assert_deterministic(
+            "let x = 1; stack [ PAY FRIEND 1 ZNT ] let y = 1 / 0;",
+            concat!(
+                "2b4bcfe0b1dfd1891a745442a81c27a9985fc64671a3c714106c7baaac795498",
+                "69b86f76ea664790660982effe541fb74556845410a08af90242e08459385b53"
+            )
+        );
+        assert_deterministic(
+            "stack [ PAY FRIEND 1 ZNT ] stack [ PAY FRIEND 1000 ZNT ]",
+            concat!(
+                "16f688544ccbb4682d5cf5b568e274ca9f53282ca6207d2aa273fe97a5757661",
+                "69b86f76ea664790660982effe541fb74556845410a08af90242e08459385b53"
+            )
+        );
+        assert_deterministic(
+            "let i = 0; while true { set i = i + 1; }",
+            concat!(
+                "84a2877b14323ba8238778e962fb36b1ce2e280300971693ab1d4c7cd92ddf87",
+                "69b86f76ea664790660982effe541fb74556845410a08af90242e08459385b53"
+            )
+        );
+    }
+
+    #[test]
+    fn globals_encode_the_same_whatever_order_they_were_declared_in() {
+        let mut first = kernel();
+        let mut second = kernel();
+
+        first.execute("let zeta = 1; let alpha = true; const mid = 'm';").unwrap();
+        second.execute("const mid = 'm'; let alpha = true; let zeta = 1;").unwrap();
+
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 4];
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 5]);
+        expected.extend(b"alpha");
+        expected.extend([2, 1, 0, 0]);
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 12]);
+        expected.extend(b"block_height");
+        expected.push(1);
+        expected.extend(0i128.to_be_bytes());
+        expected.extend([1, 0]);
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 3]);
+        expected.extend(b"mid");
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 0, 1, b'm', 1, 0]);
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 4]);
+        expected.extend(b"zeta");
+        expected.push(1);
+        expected.extend(1_000_000_000_000_000_000i128.to_be_bytes());
+        expected.extend([0, 0]);
+
+        assert_eq!(canonical_bytes(first.globals()), expected);
+        assert_eq!(canonical_bytes(second.globals()), expected);
+    }
+
+    #[test]
+    fn triggers_and_queries_encode_what_they_do_not_just_where_they_are() {
+        let pairs = [
+            ("when block_height > 10 { stack [ PAY FRIEND 1 ZNT ] }", "when block_height > 20 { stack [ PAY FRIEND 2 ZNT ] }"),
+            ("stack [ funds = GET AMOUNT ZNT WHERE WHO = PAYER ]", "stack [ funds = GET AMOUNT SDL WHERE WHO = PAYER ]")
+        ];
+
+        for (first, second) in pairs {
+            let mut first_kernel = kernel();
+            let mut second_kernel = kernel();
+            first_kernel.execute(&script(first)).unwrap();
+            second_kernel.execute(&script(second)).unwrap();
+
+            assert_ne!(canonical_bytes(&first_kernel), canonical_bytes(&second_kernel), "{}", first);
+        }
+    }
+
+    #[test]
+    fn arithmetic_is_exact() {
+        let mut kernel = kernel();
+        let execution = kernel.execute(&script("let third = 10 / 3; let sum = 0.1 + 0.2; stack [ PAY FRIEND third ZNT ]")).unwrap();
+        let globals = kernel.globals();
+
+        assert_eq!(globals["third"].value, AssignmentValue::Number("3.333333333333333333".parse().unwrap()));
+        assert_eq!(globals["sum"].value, AssignmentValue::Number("0.3".parse().unwrap()));
+        assert_eq!(execution.transactions[0].operations, vec![TransactionOperation::Transfer(Transfer {
+            payee: friend(),
+            amount: "3.333333".parse().unwrap(),
+            asset: Asset::Znt
+        })]);
+    }
+
+    #[test]
+    fn hosts_can_choose_the_rounding_mode() {
+        let source = "let third = 2 / 3; stack [ PAY FRIEND third ZNT ]";
+        let mut down = kernel();
+        let mut up = kernel();
+        down.set_rounding(RoundingMode::Down);
+        up.set_rounding(RoundingMode::Up);
+
+        let paid_down = down.execute(&script(source)).unwrap();
+        let paid_up = up.execute(&script(source)).unwrap();
+
+        let amount = |execution: &Execution| match &execution.transactions[0].operations[0] {
+            TransactionOperation::Transfer(transfer) => transfer.amount,
+            other => panic!("expected a transfer, found {:?}", other),
+        };
+
+        assert_eq!(down.globals()["third"].value, AssignmentValue::Number("0.666666666666666666".parse().unwrap()));
+        assert_eq!(up.globals()["third"].value, AssignmentValue::Number("0.666666666666666667".parse().unwrap()));
+        assert_eq!(amount(&paid_down), "0.666666".parse().unwrap());
+        assert_eq!(amount(&paid_up), "0.666667".parse().unwrap());
+    }
+
+    #[test]
+    fn new_addresses_depend_only_on_payer_and_count() {
+        let source = "stack [ first = NEW ADDRESS ] stack [ second = NEW ADDRESS ]";
+        let mut one = kernel();
+        let mut two = kernel();
+        let mut other_payer = kernel();
+        other_payer.set_payer(address(3));
+
+        one.execute(source).unwrap();
+        two.execute(source).unwrap();
+        other_payer.execute(source).unwrap();
+
+        let globals = one.globals();
+
+        assert_eq!(globals, two.globals());
+        assert_ne!(globals["first"], globals["second"]);
+        assert_ne!(globals["first"], other_payer.globals()["first"]);
+    }
+
+    #[test]
+    fn new_addresses_are_never_reused_across_kernels() {
+        let source = "stack [ first = NEW ADDRESS ] stack [ second = NEW ADDRESS ] stack [ third = NEW ADDRESS ]";
+        let mut before = kernel();
+        before.execute(source).unwrap();
+
+        let mut restarted = Kernel::new(before.ledger().clone(), DEFAULT_FUEL_LIMIT);
+        restarted.set_payer(payer());
+        restarted.execute(source).unwrap();
+
+        let created: BTreeSet<Address> = [&before, &restarted]
+            .iter()
+            .flat_map(|kernel| ["first", "second", "third"].map(|name| match &kernel.globals()[name].value {
+                AssignmentValue::Address(address) => address.clone(),
+                other => panic!("expected an address, found {:?}", other)
+            }))
+            .collect();
+
+        assert_eq!(created.len(), 6);
+        assert_eq!(restarted.ledger().address_nonce(&payer()), 6);
+    }
+
+    #[test]
+    fn running_out_of_fuel_fails_at_the_same_point() {
+        let source = "let i = 0; while true { set i = i + 1; }";
+        let mut first = kernel();
+        let mut second = kernel();
+
+        let first_error = first.execute(source).unwrap_err();
+        let second_error = second.execute(source).unwrap_err();
+
+        assert!(matches!(first_error, ZqlError::Runtime(RuntimeError::OutOfFuel { limit: DEFAULT_FUEL_LIMIT, .. })));
+        assert_eq!(first_error, second_error);
+        assert_eq!(first.fuel_used(), second.fuel_used());
+        assert!(first.fuel_used() <= DEFAULT_FUEL_LIMIT);
+    }
+
+    #[test]
+    fn loops_stop_once_their_fuel_runs_out() {
+        let items = (1..=200).map(|i| i.to_string()).collect::<Vec<String>>().join(", ");
+        let loops = [
+            String::from("let i = 0; while i < 200 { set i = i + 1; stack [ PAY FRIEND 0.1 ZNT ] }"),
+            format!("for i in [{}] {{ stack [ PAY FRIEND 0.1 ZNT ] }}", items)
+        ];
+
+        for source in loops {
+            let mut limited = Kernel::new(kernel().ledger().clone(), 500);
+            limited.set_payer(payer());
+
+            let error = limited.execute(&script(&source)).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::OutOfFuel { limit: 500, .. })), "{}: {:?}", source, error);
+            assert!(limited.fuel_used() <= 500);
+            assert!(!limited.globals().contains_key("i"));
+            assert_eq!(limited.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(40));
+
+            // The same loop runs to the end once it is supplied enough fuel
+            let mut supplied = kernel();
+            supplied.execute(&script(&source)).unwrap();
+
+            assert_eq!(supplied.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(60));
+        }
+    }
+
+    #[test]
+    fn fuel_used_is_the_same_every_run() {
+        let fuel_used = (0..RUNS)
+            .map(|_| kernel().execute(&script(FULL_SCRIPT)).unwrap().fuel_used)
+            .collect::<Vec<u64>>();
+
+        assert!(fuel_used.iter().all(|fuel| *fuel == fuel_used[0]));
+    }
+
+    #[test]
+    fn simulation_matches_execution() {
+        let mut kernel = kernel();
+        let simulation = kernel.simulate(&script(FULL_SCRIPT), kernel.ledger()).unwrap();
+        let execution = kernel.execute(&script(FULL_SCRIPT)).unwrap();
+
+        assert_eq!(simulation.transactions, execution.transactions);
+        assert_eq!(simulation.fuel_used, execution.fuel_used);
+
+        for change in &simulation.diff.balances {
+            assert_eq!(kernel.ledger().balance(&change.address, change.asset), change.after);
+        }
+    }
+
+    #[test]
+    fn failed_execution_leaves_no_trace() {
+        let mut kernel = kernel();
+        let before = format!("{:?}", kernel);
+
+        kernel.execute(&script("let x = 1; stack [ v = NEW ADDRESS ] stack [ PAY FRIEND 1 ZNT ] stack [ PAY FRIEND 1000 ZNT ]")).unwrap_err();
+        kernel.compiler.0.fuel_used = 0;
+
+        assert_eq!(format!("{:?}", kernel), before);
+    }
+
+    #[test]
+    fn failed_transact_block_leaves_no_trace() {
+        let mut kernel = kernel();
+        let before = format!("{:?}", kernel);
+
+        let error = kernel.execute(&script("stack [ TRANSACT [ PAY FRIEND 1 ZNT; CREATE 'a' 'b' ENCODING hex ] ]")).unwrap_err();
+        kernel.compiler.0.fuel_used = 0;
+
+        assert!(matches!(error, ZqlError::Runtime(RuntimeError::InvalidEncoding { .. })));
+        assert_eq!(format!("{:?}", kernel), before);
+    }
+
+    #[test]
+    fn rolling_back_undoes_nested_checkpoints() {
+        let mut kernel = kernel();
+        let before = format!("{:?}", kernel);
+
+        kernel.checkpoint();
+        kernel.execute(&script("let x = 1; stack [ PAY FRIEND 1 ZNT ]")).unwrap();
+
+        kernel.checkpoint();
+        kernel.execute(&script("let y = 2; stack [ PAY FRIEND 2 ZNT ]")).unwrap();
+        assert!(kernel.commit());
+
+        kernel.checkpoint();
+        kernel.execute(&script("stack [ PAY FRIEND 1000 ZNT ]")).unwrap_err();
+        assert_eq!(kernel.checkpoint_depth(), 2);
+        assert!(kernel.rollback());
+
+        assert_eq!(kernel.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(43));
+        assert!(kernel.rollback());
+        assert!(!kernel.rollback());
+
+        kernel.compiler.0.fuel_used = 0;
+        assert_eq!(format!("{:?}", kernel), before);
+    }
+
+    #[test]
+    fn triggers_only_observe_block_height_through_the_ledger() {
+        let mut kernel = kernel();
+        kernel.execute(&script("when block_height > 10 { stack [ PAY FRIEND 1 ZNT ] }")).unwrap();
+
+        assert!(kernel.notify_state_change().unwrap().transactions.is_empty());
+
+        kernel.ledger_mut().set_block_height(11);
+
+        assert_eq!(kernel.notify_state_change().unwrap().transactions.len(), 1);
+        assert!(kernel.notify_state_change().unwrap().transactions.is_empty());
+    }
+
+    #[test]
+    fn triggers_see_queries_refreshed_from_the_ledger() {
+        let mut kernel = kernel();
+        let source = "
+            const escrow = FRIEND;
+            let goal = 50;
+            stack [ funds = GET AMOUNT ZNT WHERE WHO = escrow ]
+
+            when funds >= goal {
+                stack [ PAY escrow 1 ZNT ]
+            }
+
+            if true {
+                stack [ local_funds = GET AMOUNT ZNT WHERE WHO = escrow ]
+
+                when local_funds >= 55 {
+                    stack [ PAY escrow 2 ZNT ]
+                }
+            }
+        ";
+
+        kernel.execute(&script(source)).unwrap();
+        kernel.ledger_mut().add_output(friend(), Asset::Znt, Decimal::from_int(10)).unwrap();
+
+        let execution = kernel.notify_state_change().unwrap();
+
+        assert_eq!(execution.transactions.len(), 1);
+        assert_eq!(kernel.globals()["funds"].value, AssignmentValue::Number(Decimal::from_int(50)));
+
+        kernel.ledger_mut().add_output(friend(), Asset::Znt, Decimal::from_int(10)).unwrap();
+
+        assert_eq!(kernel.notify_state_change().unwrap().transactions.len(), 1);
+        assert!(kernel.compiler.0.triggers.is_empty());
+    }
+
+    #[test]
+    fn triggers_see_the_scripts_own_transactions_once_applied() {
+        let mut kernel = kernel();
+        let source = "
+            const escrow = FRIEND;
+            stack [ PAY escrow 20 ZNT ]
+            stack [ funds = GET AMOUNT ZNT WHERE WHO = escrow ]
+
+            when funds >= 60 {
+                stack [ PAY escrow 1 ZNT ]
+            }
+        ";
+
+        assert_eq!(kernel.execute(&script(source)).unwrap().transactions.len(), 1);
+        assert_eq!(kernel.notify_state_change().unwrap().transactions.len(), 1);
+    }
+
+    #[test]
+    fn conditionals_run_only_the_branch_their_condition_selects() {
+        let source = "
+            let branch = 'none';
+
+            if amount > 10 {
+                set branch = 'large';
+                stack [ PAY FRIEND amount ZNT ]
+            } else if amount > 0 {
+                set branch = 'small';
+                stack [ PAY FRIEND 1 ZNT ]
+            } else {
+                set branch = 'nothing';
+            }
+        ";
+
+        for (amount, branch, paid) in [(20, "large", 20), (5, "small", 1), (0, "nothing", 0)] {
+            let mut kernel = kernel();
+            let execution = kernel.execute(&script(&format!("let amount = {};{}", amount, source))).unwrap();
+
+            assert_eq!(kernel.globals()["branch"].value, AssignmentValue::Text(String::from(branch)));
+            assert_eq!(execution.transactions.len(), (paid > 0) as usize);
+            assert_eq!(kernel.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(40 + paid));
+        }
+    }
+
+    #[test]
+    fn conditions_must_be_booleans() {
+        for condition in ["1", "'yes'", "[true]"] {
+            let error = kernel().execute(&format!("if {} {{ let x = 1; }}", condition)).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::TypeMismatch { .. })), "{}: {:?}", condition, error);
+        }
+    }
+
+    #[test]
+    fn and_and_or_only_evaluate_what_decides_their_result() {
+        let mut kernel = kernel();
+        kernel.execute("let a = false and 1 / 0 > 0; let b = true or 1 / 0 > 0; let c = false and 1;").unwrap();
+
+        assert_eq!(kernel.globals()["a"].value, AssignmentValue::Bool(false));
+        assert_eq!(kernel.globals()["b"].value, AssignmentValue::Bool(true));
+        assert_eq!(kernel.globals()["c"].value, AssignmentValue::Bool(false));
+
+        for source in ["let d = true and 1 / 0 > 0;", "let d = false or 1 / 0 > 0;"] {
+            let error = kernel.execute(source).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::DivisionByZero { .. })), "{}: {:?}", source, error);
+        }
+    }
+
+    #[test]
+    fn logical_and_comparison_operators_check_the_types_of_their_operands() {
+        let mismatched = [
+            "1 and true",
+            "true and 1",
+            "false or 'yes'",
+            "not 1",
+            "1 < true",
+            "'a' < 'b'",
+            "'a' == 1",
+            "[1] != 1"
+        ];
+
+        for expression in mismatched {
+            let error = kernel().execute(&format!("let x = {};", expression)).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::TypeMismatch { .. })), "{}: {:?}", expression, error);
+        }
+    }
+
+    #[test]
+    fn chained_payments_form_a_single_transaction() {
+        let mut kernel = kernel();
+        let execution = kernel.execute(&script("stack [ PAY FRIEND 10 ZNT AND PAY FRIEND 5 SDL AND PAY PAYER 1 ZNT ]")).unwrap();
+        let transfer = |payee: Address, amount: i64, asset: Asset| TransactionOperation::Transfer(Transfer {
+            payee,
+            amount: Decimal::from_int(amount),
+            asset
+        });
+
+        assert_eq!(execution.transactions.len(), 1);
+        assert_eq!(execution.transactions[0].operations, vec![
+            transfer(friend(), 10, Asset::Znt),
+            transfer(friend(), 5, Asset::Sdl),
+            transfer(payer(), 1, Asset::Znt)
+        ]);
+        assert_eq!(kernel.ledger().balance(&friend(), Asset::Sdl), Decimal::from_int(5));
+    }
+
+    #[test]
+    fn chained_payments_are_all_made_or_none_are() {
+        let failing = [
+            "stack [ PAY FRIEND 10 ZNT AND PAY FRIEND 1000 SDL ]",
+            "stack [ PAY FRIEND 10 ZNT AND PAY FRIEND 0 SDL ]",
+            "let ten = 'ten'; stack [ PAY FRIEND 10 ZNT AND PAY FRIEND ten SDL ]"
+        ];
+
+        for source in failing {
+            let mut kernel = kernel();
+            let error = kernel.execute(&script(source)).unwrap_err();
+
+            assert!(matches!(
+                error,
+                ZqlError::Runtime(RuntimeError::LedgerRejected { .. } | RuntimeError::InvalidAmount { .. } | RuntimeError::TypeMismatch { .. })
+            ), "{}: {:?}", source, error);
+            assert!(kernel.ledger().history(&friend()).is_empty());
+            assert_eq!(kernel.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(40));
+            assert_eq!(kernel.ledger().balance(&payer(), Asset::Znt), Decimal::from_int(100));
+        }
+    }
+
+    #[test]
+    fn amount_queries_total_the_balances_that_pass_their_filters() {
+        let queries = [
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER]", 140),
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER, FRIEND]", 140),
+            ("GET AMOUNT SDL WHERE WHO IN [FRIEND, PAYER]", 100),
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER] AND AMOUNT > 50", 100),
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER] AND AMOUNT >= 40 AND AMOUNT < 100", 40),
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER] AND AMOUNT != 40", 100),
+            ("GET AMOUNT ZNT WHERE WHO IN [FRIEND, PAYER] AND AMOUNT > 100", 0)
+        ];
+
+        for (query, expected) in queries {
+            let mut kernel = kernel();
+            kernel.execute(&script(&format!("stack [ total = {} ]", query))).unwrap();
+
+            assert_eq!(kernel.globals()["total"].value, AssignmentValue::Number(Decimal::from_int(expected)), "{}", query);
+        }
+    }
+
+    #[test]
+    fn who_in_needs_a_list_of_addresses() {
+        let sources = [
+            "stack [ total = GET AMOUNT ZNT WHERE WHO IN FRIEND ]",
+            "stack [ total = GET AMOUNT ZNT WHERE WHO IN [FRIEND, 1] ]",
+            "let friends = 'FRIEND'; stack [ total = GET AMOUNT ZNT WHERE WHO IN friends ]"
+        ];
+
+        for source in sources {
+            let error = kernel().execute(&script(source)).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::TypeMismatch { .. })), "{}: {:?}", source, error);
+        }
+    }
+
+    #[test]
+    fn constants_stay_constant_across_scripts() {
+        let mut kernel = kernel();
+        kernel.execute("const limit = 1; let count = 1;").unwrap();
+
+        let reassigned = kernel.execute("set limit = 2;").unwrap_err();
+        let redeclared = kernel.execute("let count = 2;").unwrap_err();
+        kernel.execute("set count = 2;").unwrap();
+
+        assert!(matches!(reassigned, ZqlError::Semantic(SemanticError::ConstantReassignment { .. })));
+        assert!(matches!(redeclared, ZqlError::Semantic(SemanticError::AlreadyDeclared { .. })));
+        assert_eq!(kernel.globals()["count"].value, AssignmentValue::Number(Decimal::from_int(2)));
+    }
+
+    #[test]
+    fn records_can_be_created_updated_and_deleted() {
+        let mut kernel = kernel();
+
+        kernel.execute(&script("stack [ CREATE 'profile' '{\"name\": \"zeta\", \"tags\": [1, 2.5]}' ENCODING json ]")).unwrap();
+        let created = kernel.ledger().record("profile").unwrap();
+
+        kernel.execute(&script("stack [ UPDATE 'profile' 'cafe' ENCODING hex WHERE WHO = PAYER ]")).unwrap();
+        let updated = kernel.ledger().record("profile").unwrap();
+
+        kernel.execute(&script("stack [ DELETE 'profile' WHERE WHO = PAYER ]")).unwrap();
+
+        assert_eq!((created.owner, created.encoding), (payer(), DataEncoding::Json));
+        assert_eq!((updated.content.as_str(), updated.encoding), ("cafe", DataEncoding::Hex));
+        assert_eq!(kernel.ledger().record("profile"), None);
+    }
+
+    #[test]
+    fn records_can_only_be_changed_through_their_owner() {
+        let mut kernel = kernel();
+        kernel.execute("stack [ CREATE 'profile' 'first' ENCODING utf8 ]").unwrap();
+
+        let updated = kernel.execute(&script("stack [ UPDATE 'profile' 'second' ENCODING utf8 WHERE WHO = FRIEND ]")).unwrap_err();
+        let deleted = kernel.execute(&script("stack [ DELETE 'profile' WHERE WHO = FRIEND ]")).unwrap_err();
+        let recreated = kernel.execute("stack [ CREATE 'profile' 'second' ENCODING utf8 ]").unwrap_err();
+
+        for error in [updated, deleted, recreated] {
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::LedgerRejected { .. })), "{:?}", error);
+        }
+
+        assert_eq!(kernel.ledger().record("profile").unwrap().content, "first");
+    }
+
+    #[test]
+    fn json_records_must_be_well_formed() {
+        let malformed = ["", "{", "{\"a\": }", "[1, 2,]", "{\"a\": 1} {\"b\": 2}", "zeta", "01"];
+
+        for content in malformed {
+            let mut kernel = kernel();
+            let source = format!("let content = '{}'; stack [ CREATE 'profile' content ENCODING json ]", content);
+
+            let error = kernel.execute(&source).unwrap_err();
+
+            assert!(matches!(error, ZqlError::Runtime(RuntimeError::InvalidEncoding { .. })), "{}: {:?}", content, error);
+            assert_eq!(kernel.ledger().record("profile"), None);
+        }
+    }
+
+    #[test]
+    fn only_permitted_assets_can_be_minted() {
+        let mut kernel = kernel();
+        kernel.execute(&script("stack [ NEW ZNT 5 TO FRIEND ]")).unwrap();
+
+        let refused = [
+            "stack [ NEW SDL 5 TO FRIEND ]",
+            "stack [ TRANSACT [ NEW ZNT 5 TO FRIEND; NEW SDL 5 TO FRIEND ] ]"
+        ];
+
+        for source in refused {
+            let error = kernel.execute(&script(source)).unwrap_err();
+
+            assert_eq!(error, ZqlError::Runtime(RuntimeError::MintNotPermitted {
+                asset: String::from("SDL"),
+                span: error.span()
+            }), "{}", source);
+        }
+
+        kernel.set_mintable_assets(Vec::new());
+        let error = kernel.execute(&script("stack [ NEW ZNT 5 TO FRIEND ]")).unwrap_err();
+
+        assert!(matches!(error, ZqlError::Runtime(RuntimeError::MintNotPermitted { .. })));
+        assert_eq!(kernel.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(45));
+        assert_eq!(kernel.ledger().balance(&friend(), Asset::Sdl), Decimal::zero());
+    }
+
+    #[test]
+    fn transact_performs_operations_in_order() {
+        let mut kernel = kernel();
+
+        let paid_before_minting = kernel.execute(&script("stack [ TRANSACT [ PAY FRIEND 200 ZNT; NEW ZNT 150 TO PAYER ] ]")).unwrap_err();
+        kernel.execute(&script("stack [ TRANSACT [ NEW ZNT 150 TO PAYER; PAY FRIEND 200 ZNT ] ]")).unwrap();
+
+        assert!(matches!(paid_before_minting, ZqlError::Runtime(RuntimeError::LedgerRejected { .. })));
+        assert_eq!(kernel.ledger().balance(&payer(), Asset::Znt), Decimal::from_int(50));
+        assert_eq!(kernel.ledger().balance(&friend(), Asset::Znt), Decimal::from_int(240));
+    }
+
+    #[test]
+    fn transact_can_replace_a_record_in_order() {
+        let mut kernel = kernel();
+        kernel.execute("stack [ CREATE 'profile' 'first' ENCODING utf8 ]").unwrap();
+
+        let execution = kernel.execute(&script("
+            stack [ TRANSACT [ DELETE 'profile' WHERE WHO = PAYER; CREATE 'profile' 'second' ENCODING utf8 ] ]
+        ")).unwrap();
+
+        assert!(matches!(
+            execution.transactions[0].operations.as_slice(),
+            [TransactionOperation::Data(DataOperation::Delete { .. }), TransactionOperation::Data(DataOperation::Create { .. })]
+        ));
+        assert_eq!(kernel.ledger().record("profile").unwrap().content, "second");
+    }
+}
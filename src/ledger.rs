@@ -0,0 +1,794 @@
+//! Access to the state of the ledger. Scripts read balances, history and data
+//! records through the `Ledger` trait, and the kernel applies the transactions
+//! they produce through it, so neither depends on how or where the host keeps
+//! that state.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::address::Address;
+use crate::canonical::Canonical;
+use crate::decimal::Decimal;
+use crate::transaction::{Asset, DataEncoding, DataOperation, TransactionIntent, TransactionOperation};
+
+/// A transfer the ledger has already recorded
+#[derive(Debug, Clone, PartialEq)]
+pub struct LedgerEntry {
+    /// Position of the entry in the history of the ledger, unique to each entry
+    pub sequence: u64,
+    pub payer: Address,
+    pub payee: Address,
+    pub amount: Decimal,
+    pub asset: Asset
+}
+
+/// An amount of an asset held by an address that hasn't been spent yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnspentOutput {
+    /// Identifier of the output, unique to each output the ledger creates
+    pub id: u64,
+    pub owner: Address,
+    pub amount: Decimal,
+    pub asset: Asset
+}
+
+/// A data record stored on the ledger
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataRecord {
+    pub key: String,
+    pub owner: Address,
+    pub content: String,
+    pub encoding: DataEncoding
+}
+
+/// Reasons the ledger can refuse a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerError {
+    InsufficientFunds { address: Address, asset: Asset },
+    RecordExists { key: String },
+    RecordNotFound { key: String },
+    NotRecordOwner { key: String },
+    AddressInUse { address: Address },
+    Overflow
+}
+
+/// State of the ledger as seen by scripts and the kernel
+pub trait Ledger {
+    /// Gets the balance an address holds of an asset
+    ///
+    /// ### Arguments
+    ///
+    /// * `address` - The address to get the balance of
+    /// * `asset`   - The asset to get the balance of
+    fn balance(&self, address: &Address, asset: Asset) -> Decimal;
+
+    /// Gets every recorded transfer the address paid or received, oldest first
+    ///
+    /// ### Arguments
+    ///
+    /// * `address` - The address to get the history of
+    fn history(&self, address: &Address) -> Vec<LedgerEntry>;
+
+    /// Gets every output the address holds that hasn't been spent, oldest first
+    ///
+    /// ### Arguments
+    ///
+    /// * `address` - The address to get the outputs of
+    fn unspent_outputs(&self, address: &Address) -> Vec<UnspentOutput>;
+
+    /// Gets the data record stored under a key, if there is one
+    ///
+    /// ### Arguments
+    ///
+    /// * `key`     - The key of the record
+    fn record(&self, key: &str) -> Option<DataRecord>;
+
+    /// Gets the height of the latest block
+    fn block_height(&self) -> u64;
+
+    /// Gets how many addresses the payer has created with NEW ADDRESS. The
+    /// next address the payer creates is derived from this count.
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`   - The address creating new addresses
+    fn address_nonce(&self, payer: &Address) -> u64;
+
+    /// Applies transactions to the ledger in order. Either every transaction
+    /// is applied or, if any part of one is refused, none of them are and the
+    /// index of the transaction refused is given with the error.
+    ///
+    /// ### Arguments
+    ///
+    /// * `transactions`    - The transactions to apply
+    fn apply_all(&mut self, transactions: &[TransactionIntent]) -> Result<(), (usize, LedgerError)>;
+}
+
+/// A ledger held entirely in memory. Funds are held as unspent outputs, which
+/// are spent oldest first with any change returned to the payer as a new output.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryLedger {
+    outputs: BTreeMap<u64, UnspentOutput>,
+    next_output_id: u64,
+    balances: BTreeMap<(Address, Asset), Decimal>,
+    entries: Vec<LedgerEntry>,
+    records: BTreeMap<String, DataRecord>,
+    address_nonces: BTreeMap<Address, u64>,
+    created_addresses: BTreeSet<Address>,
+    block_height: u64
+}
+
+/// Changes made to an `InMemoryLedger` by transactions that are still being
+/// applied. Outputs created by the transactions are held here, while outputs
+/// of the ledger they spend are only marked as spent.
+#[derive(Debug)]
+struct StagedChanges {
+    spent: BTreeSet<u64>,
+    outputs: BTreeMap<u64, UnspentOutput>,
+    next_output_id: u64,
+    balances: BTreeMap<(Address, Asset), Decimal>,
+    entries: Vec<LedgerEntry>,
+    /// Records created, updated or deleted, with deleted records as `None`
+    records: BTreeMap<String, Option<DataRecord>>,
+    address_nonces: BTreeMap<Address, u64>,
+    created_addresses: BTreeSet<Address>
+}
+
+/// An `InMemoryLedger` with changes staged on top of it. Reads see the staged
+/// changes in place of the state they replace, and the ledger itself is left
+/// alone until the changes are committed to it.
+#[derive(Debug)]
+struct Stage<'a> {
+    ledger: &'a InMemoryLedger,
+    changes: StagedChanges
+}
+
+/// A view of a ledger that reads from it until a transaction is applied, at
+/// which point the ledger is copied and the copy changed instead. The ledger
+/// being viewed is never changed.
+#[derive(Debug, Clone)]
+pub struct LedgerView<'a, L: Ledger + Clone>(Cow<'a, L>);
+
+/// How the balance an address holds of an asset changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceChange {
+    pub address: Address,
+    pub asset: Asset,
+    pub before: Decimal,
+    pub after: Decimal
+}
+
+/// How the data record stored under a key changed. A record that didn't
+/// exist before, or no longer exists after, is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordChange {
+    pub key: String,
+    pub before: Option<DataRecord>,
+    pub after: Option<DataRecord>
+}
+
+/// The differences between two states of a ledger, ordered by address and
+/// asset for balances and by key for records
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LedgerDiff {
+    pub balances: Vec<BalanceChange>,
+    pub records: Vec<RecordChange>
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl InMemoryLedger {
+    /// Creates an empty ledger
+    pub fn new() -> InMemoryLedger {
+        InMemoryLedger::default()
+    }
+
+    /// Gives an address a new output of an asset, without recording a transfer.
+    /// This is intended for setting up the ledger before any scripts run.
+    ///
+    /// ### Arguments
+    ///
+    /// * `owner`   - The address to give the output to
+    /// * `asset`   - The asset of the output
+    /// * `amount`  - The amount of the output
+    pub fn add_output(&mut self, owner: Address, asset: Asset, amount: Decimal) -> Result<(), LedgerError> {
+        let mut stage = Stage::new(self);
+        stage.add_output(owner, asset, amount)?;
+
+        let changes = stage.changes;
+        self.commit(changes);
+
+        Ok(())
+    }
+
+    /// Sets the height of the latest block
+    ///
+    /// ### Arguments
+    ///
+    /// * `block_height`    - The new block height
+    pub fn set_block_height(&mut self, block_height: u64) {
+        self.block_height = block_height;
+    }
+
+    /// Takes changes staged on top of the ledger, once every transaction
+    /// they were made by has been applied
+    ///
+    /// ### Arguments
+    ///
+    /// * `changes` - The changes to take
+    fn commit(&mut self, changes: StagedChanges) {
+        for id in changes.spent {
+            self.outputs.remove(&id);
+        }
+
+        for (key, record) in changes.records {
+            match record {
+                Some(record) => self.records.insert(key, record),
+                None => self.records.remove(&key)
+            };
+        }
+
+        self.outputs.extend(changes.outputs);
+        self.next_output_id = changes.next_output_id;
+        self.balances.extend(changes.balances);
+        self.entries.extend(changes.entries);
+        self.address_nonces.extend(changes.address_nonces);
+        self.created_addresses.extend(changes.created_addresses);
+    }
+}
+
+impl<'a> Stage<'a> {
+    /// Creates a stage with no changes on top of a ledger
+    ///
+    /// ### Arguments
+    ///
+    /// * `ledger`  - The ledger to stage changes on top of
+    fn new(ledger: &'a InMemoryLedger) -> Stage<'a> {
+        Stage {
+            ledger,
+            changes: StagedChanges {
+                spent: BTreeSet::new(),
+                outputs: BTreeMap::new(),
+                next_output_id: ledger.next_output_id,
+                balances: BTreeMap::new(),
+                entries: Vec::new(),
+                records: BTreeMap::new(),
+                address_nonces: BTreeMap::new(),
+                created_addresses: BTreeSet::new()
+            }
+        }
+    }
+
+    /// Stages the changes made by a transaction on behalf of its payer
+    ///
+    /// ### Arguments
+    ///
+    /// * `transaction` - The transaction to apply
+    fn apply(&mut self, transaction: &TransactionIntent) -> Result<(), LedgerError> {
+        let payer = &transaction.payer;
+
+        for operation in &transaction.operations {
+            match operation {
+                TransactionOperation::Transfer(transfer) => {
+                    self.spend(payer, transfer.asset, transfer.amount)?;
+                    self.credit(payer, &transfer.payee, transfer.asset, transfer.amount)?;
+                }
+                TransactionOperation::Mint(mint) => {
+                    self.credit(payer, &mint.payee, mint.asset, mint.amount)?;
+                }
+                TransactionOperation::Data(operation) => {
+                    self.apply_data_operation(payer, operation)?;
+                }
+                TransactionOperation::CreateAddress(address) => {
+                    self.create_address(payer, address)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the balance an address holds of an asset, including staged changes
+    ///
+    /// ### Arguments
+    ///
+    /// * `address` - The address to get the balance of
+    /// * `asset`   - The asset to get the balance of
+    fn balance(&self, address: &Address, asset: Asset) -> Decimal {
+        match self.changes.balances.get(&(address.clone(), asset)) {
+            Some(balance) => *balance,
+            None => self.ledger.balance(address, asset)
+        }
+    }
+
+    /// Gets the record stored under a key, including staged changes
+    ///
+    /// ### Arguments
+    ///
+    /// * `key`     - The key of the record
+    fn record(&self, key: &str) -> Option<&DataRecord> {
+        match self.changes.records.get(key) {
+            Some(record) => record.as_ref(),
+            None => self.ledger.records.get(key)
+        }
+    }
+
+    /// Gives an address a new output of an asset
+    ///
+    /// ### Arguments
+    ///
+    /// * `owner`   - The address to give the output to
+    /// * `asset`   - The asset of the output
+    /// * `amount`  - The amount of the output
+    fn add_output(&mut self, owner: Address, asset: Asset, amount: Decimal) -> Result<(), LedgerError> {
+        let balance = self.balance(&owner, asset)
+            .checked_add(amount)
+            .map_err(|_| LedgerError::Overflow)?;
+        let id = self.changes.next_output_id;
+
+        self.changes.balances.insert((owner.clone(), asset), balance);
+        self.changes.outputs.insert(id, UnspentOutput { id, owner, amount, asset });
+        self.changes.next_output_id += 1;
+
+        Ok(())
+    }
+
+    /// Spends the oldest outputs of the owner until they cover the amount,
+    /// returning any change to the owner as a new output. Outputs created
+    /// by the staged changes are newer than any of the ledger's own.
+    ///
+    /// ### Arguments
+    ///
+    /// * `owner`   - The address spending the outputs
+    /// * `asset`   - The asset to spend
+    /// * `amount`  - The amount to spend
+    fn spend(&mut self, owner: &Address, asset: Asset, amount: Decimal) -> Result<(), LedgerError> {
+        let mut spent = Vec::new();
+        let mut total = Decimal::zero();
+
+        let unspent = self.ledger.outputs
+            .values()
+            .filter(|output| !self.changes.spent.contains(&output.id))
+            .chain(self.changes.outputs.values());
+
+        for output in unspent {
+            if total >= amount {
+                break;
+            }
+
+            if &output.owner == owner && output.asset == asset {
+                spent.push(output.id);
+                total = total.checked_add(output.amount).map_err(|_| LedgerError::Overflow)?;
+            }
+        }
+
+        if total < amount {
+            return Err(LedgerError::InsufficientFunds { address: owner.clone(), asset });
+        }
+
+        for id in spent {
+            if self.changes.outputs.remove(&id).is_none() {
+                self.changes.spent.insert(id);
+            }
+        }
+
+        let change = total.checked_sub(amount).map_err(|_| LedgerError::Overflow)?;
+        let balance = self.balance(owner, asset)
+            .checked_sub(total)
+            .map_err(|_| LedgerError::Overflow)?;
+
+        self.changes.balances.insert((owner.clone(), asset), balance);
+
+        if !change.is_zero() {
+            self.add_output(owner.clone(), asset, change)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives the payee a new output and records the transfer in the history
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`   - The address that paid, or issued a mint
+    /// * `payee`   - The address that was paid
+    /// * `asset`   - The asset paid in
+    /// * `amount`  - The amount paid
+    fn credit(&mut self, payer: &Address, payee: &Address, asset: Asset, amount: Decimal) -> Result<(), LedgerError> {
+        self.add_output(payee.clone(), asset, amount)?;
+        self.changes.entries.push(LedgerEntry {
+            sequence: (self.ledger.entries.len() + self.changes.entries.len()) as u64,
+            payer: payer.clone(),
+            payee: payee.clone(),
+            amount,
+            asset
+        });
+
+        Ok(())
+    }
+
+    /// Applies a data operation on behalf of the payer. A record can only be
+    /// changed by its owner, and only if the owner is one of those named.
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`       - The address the operation is on behalf of
+    /// * `operation`   - The data operation to apply
+    fn apply_data_operation(&mut self, payer: &Address, operation: &DataOperation) -> Result<(), LedgerError> {
+        let (key, record) = match operation {
+            DataOperation::Create { key, content, encoding } => {
+                if self.record(key).is_some() {
+                    return Err(LedgerError::RecordExists { key: key.clone() });
+                }
+
+                (key, Some(DataRecord {
+                    key: key.clone(),
+                    owner: payer.clone(),
+                    content: content.clone(),
+                    encoding: *encoding
+                }))
+            }
+            DataOperation::Update { key, content, encoding, owners } => {
+                let mut record = self.owned_record(payer, key, owners)?.clone();
+                record.content = content.clone();
+                record.encoding = *encoding;
+
+                (key, Some(record))
+            }
+            DataOperation::Delete { key, owners } => {
+                self.owned_record(payer, key, owners)?;
+
+                (key, None)
+            }
+        };
+
+        self.changes.records.insert(key.clone(), record);
+
+        Ok(())
+    }
+
+    /// Records an address created by the payer, counting it towards the nonce
+    /// the payer's next address is derived from
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`   - The address that created the new address
+    /// * `address` - The address created
+    fn create_address(&mut self, payer: &Address, address: &Address) -> Result<(), LedgerError> {
+        if self.ledger.created_addresses.contains(address) || !self.changes.created_addresses.insert(address.clone()) {
+            return Err(LedgerError::AddressInUse { address: address.clone() });
+        }
+
+        let nonce = match self.changes.address_nonces.get(payer) {
+            Some(nonce) => *nonce,
+            None => self.ledger.address_nonce(payer)
+        };
+
+        self.changes.address_nonces.insert(payer.clone(), nonce + 1);
+
+        Ok(())
+    }
+
+    /// Gets the record stored under a key, provided its owner is one of those
+    /// named and is the payer
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`   - The address changing the record
+    /// * `key`     - The key of the record
+    /// * `owners`  - The addresses the record must be owned by one of
+    fn owned_record(&self, payer: &Address, key: &str, owners: &[Address]) -> Result<&DataRecord, LedgerError> {
+        let record = self
+            .record(key)
+            .filter(|record| owners.contains(&record.owner))
+            .ok_or_else(|| LedgerError::RecordNotFound { key: key.to_string() })?;
+
+        if &record.owner != payer {
+            return Err(LedgerError::NotRecordOwner { key: key.to_string() });
+        }
+
+        Ok(record)
+    }
+}
+
+impl Canonical for InMemoryLedger {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.outputs.encode(out);
+        self.next_output_id.encode(out);
+        self.balances.encode(out);
+        self.entries.encode(out);
+        self.records.encode(out);
+        self.address_nonces.encode(out);
+        self.created_addresses.encode(out);
+        self.block_height.encode(out);
+    }
+}
+
+impl Ledger for InMemoryLedger {
+    fn balance(&self, address: &Address, asset: Asset) -> Decimal {
+        self.balances.get(&(address.clone(), asset)).copied().unwrap_or_default()
+    }
+
+    fn history(&self, address: &Address) -> Vec<LedgerEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| &entry.payer == address || &entry.payee == address)
+            .cloned()
+            .collect()
+    }
+
+    fn unspent_outputs(&self, address: &Address) -> Vec<UnspentOutput> {
+        self.outputs
+            .values()
+            .filter(|output| &output.owner == address)
+            .cloned()
+            .collect()
+    }
+
+    fn record(&self, key: &str) -> Option<DataRecord> {
+        self.records.get(key).cloned()
+    }
+
+    fn block_height(&self) -> u64 {
+        self.block_height
+    }
+
+    fn address_nonce(&self, payer: &Address) -> u64 {
+        self.address_nonces.get(payer).copied().unwrap_or_default()
+    }
+
+    fn apply_all(&mut self, transactions: &[TransactionIntent]) -> Result<(), (usize, LedgerError)> {
+        // Changes are staged on top of the ledger, which only takes them once
+        // every transaction has been applied
+        let mut stage = Stage::new(self);
+
+        for (index, transaction) in transactions.iter().enumerate() {
+            stage.apply(transaction).map_err(|error| (index, error))?;
+        }
+
+        let changes = stage.changes;
+        self.commit(changes);
+
+        Ok(())
+    }
+}
+
+impl<'a, L: Ledger + Clone> LedgerView<'a, L> {
+    /// Creates a view of a ledger
+    ///
+    /// ### Arguments
+    ///
+    /// * `ledger`  - The ledger to view
+    pub fn new(ledger: &'a L) -> LedgerView<'a, L> {
+        LedgerView(Cow::Borrowed(ledger))
+    }
+}
+
+impl<L: Ledger + Clone> Ledger for LedgerView<'_, L> {
+    fn balance(&self, address: &Address, asset: Asset) -> Decimal {
+        self.0.balance(address, asset)
+    }
+
+    fn history(&self, address: &Address) -> Vec<LedgerEntry> {
+        self.0.history(address)
+    }
+
+    fn unspent_outputs(&self, address: &Address) -> Vec<UnspentOutput> {
+        self.0.unspent_outputs(address)
+    }
+
+    fn record(&self, key: &str) -> Option<DataRecord> {
+        self.0.record(key)
+    }
+
+    fn block_height(&self) -> u64 {
+        self.0.block_height()
+    }
+
+    fn address_nonce(&self, payer: &Address) -> u64 {
+        self.0.address_nonce(payer)
+    }
+
+    fn apply_all(&mut self, transactions: &[TransactionIntent]) -> Result<(), (usize, LedgerError)> {
+        self.0.to_mut().apply_all(transactions)
+    }
+}
+
+impl LedgerDiff {
+    /// Finds what changed between two states of a ledger. Only the balances
+    /// and records the transactions could have touched are compared.
+    ///
+    /// ### Arguments
+    ///
+    /// * `before`          - The ledger before the transactions were applied
+    /// * `after`           - The ledger after the transactions were applied
+    /// * `transactions`    - The transactions applied
+    pub fn between(before: &dyn Ledger, after: &dyn Ledger, transactions: &[TransactionIntent]) -> LedgerDiff {
+        let mut balance_keys = BTreeSet::new();
+        let mut record_keys = BTreeSet::new();
+
+        for transaction in transactions {
+            for operation in &transaction.operations {
+                match operation {
+                    TransactionOperation::Transfer(transfer) | TransactionOperation::Mint(transfer) => {
+                        balance_keys.insert((transaction.payer.clone(), transfer.asset));
+                        balance_keys.insert((transfer.payee.clone(), transfer.asset));
+                    }
+                    TransactionOperation::Data(
+                        DataOperation::Create { key, .. } |
+                        DataOperation::Update { key, .. } |
+                        DataOperation::Delete { key, .. }
+                    ) => {
+                        record_keys.insert(key.clone());
+                    }
+                    TransactionOperation::CreateAddress(_) => {}
+                }
+            }
+        }
+
+        let balances = balance_keys
+            .into_iter()
+            .map(|(address, asset)| BalanceChange {
+                before: before.balance(&address, asset),
+                after: after.balance(&address, asset),
+                address,
+                asset
+            })
+            .filter(|change| change.before != change.after)
+            .collect();
+
+        let records = record_keys
+            .into_iter()
+            .map(|key| RecordChange {
+                before: before.record(&key),
+                after: after.record(&key),
+                key
+            })
+            .filter(|change| change.before != change.after)
+            .collect();
+
+        LedgerDiff { balances, records }
+    }
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::InsufficientFunds { address, asset } => {
+                write!(f, "{} does not hold enough {}", address, asset)
+            }
+            LedgerError::RecordExists { key } => {
+                write!(f, "a record with the key '{}' already exists", key)
+            }
+            LedgerError::RecordNotFound { key } => {
+                write!(f, "no record with the key '{}' is owned by the addresses given", key)
+            }
+            LedgerError::NotRecordOwner { key } => {
+                write!(f, "the record with the key '{}' can only be changed by its owner", key)
+            }
+            LedgerError::AddressInUse { address } => {
+                write!(f, "{} has already been created", address)
+            }
+            LedgerError::Overflow => {
+                write!(f, "the balance is too large to be represented")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::{Address, ADDRESS_LENGTH};
+    use crate::canonical::canonical_bytes;
+    use crate::decimal::Decimal;
+    use crate::lexer::Span;
+    use crate::transaction::{Asset, TransactionIntent, TransactionOperation, Transfer};
+    use super::{InMemoryLedger, Ledger, LedgerError};
+
+    fn alice() -> Address {
+        Address::from_bytes([1; ADDRESS_LENGTH])
+    }
+
+    fn bob() -> Address {
+        Address::from_bytes([2; ADDRESS_LENGTH])
+    }
+
+    fn pay(payee: Address, amount: i64) -> TransactionOperation {
+        TransactionOperation::Transfer(Transfer { payee, amount: Decimal::from_int(amount), asset: Asset::Znt })
+    }
+
+    fn transaction(operations: Vec<TransactionOperation>) -> TransactionIntent {
+        TransactionIntent::new(alice(), Span::default(), operations)
+    }
+
+    /// Creates a ledger where alice holds outputs of 10, 20 and 30 ZNT, in that order
+    fn ledger() -> InMemoryLedger {
+        let mut ledger = InMemoryLedger::new();
+
+        for amount in [10, 20, 30] {
+            ledger.add_output(alice(), Asset::Znt, Decimal::from_int(amount)).unwrap();
+        }
+
+        ledger
+    }
+
+    /// Gets the id and amount of each output the address holds, oldest first
+    fn outputs(ledger: &InMemoryLedger, address: &Address) -> Vec<(u64, Decimal)> {
+        ledger.unspent_outputs(address).iter().map(|output| (output.id, output.amount)).collect()
+    }
+
+    #[test]
+    fn payments_spend_the_oldest_outputs_first() {
+        let mut ledger = ledger();
+        ledger.apply_all(&[transaction(vec![pay(bob(), 30)])]).unwrap();
+
+        assert_eq!(outputs(&ledger, &alice()), vec![(2, Decimal::from_int(30))]);
+        assert_eq!(outputs(&ledger, &bob()), vec![(3, Decimal::from_int(30))]);
+        assert_eq!(ledger.balance(&alice(), Asset::Znt), Decimal::from_int(30));
+        assert_eq!(ledger.history(&bob()).len(), 1);
+    }
+
+    #[test]
+    fn change_is_returned_to_the_payer_as_a_new_output() {
+        let mut ledger = ledger();
+        ledger.apply_all(&[transaction(vec![pay(bob(), 25)])]).unwrap();
+
+        assert_eq!(outputs(&ledger, &alice()), vec![(2, Decimal::from_int(30)), (3, Decimal::from_int(5))]);
+        assert_eq!(ledger.balance(&alice(), Asset::Znt), Decimal::from_int(35));
+
+        // Change from one payment can be spent by the next in the same transaction
+        ledger.apply_all(&[transaction(vec![pay(bob(), 31), pay(bob(), 4)])]).unwrap();
+
+        assert_eq!(outputs(&ledger, &alice()), vec![]);
+        assert_eq!(outputs(&ledger, &bob()).len(), 3);
+        assert_eq!(ledger.balance(&bob(), Asset::Znt), Decimal::from_int(60));
+    }
+
+    #[test]
+    fn payments_must_be_covered_by_unspent_outputs() {
+        let mut ledger = ledger();
+        let before = canonical_bytes(&ledger);
+        let refused = [
+            transaction(vec![pay(bob(), 61)]),
+            transaction(vec![pay(bob(), 30), pay(bob(), 31)]),
+            TransactionIntent::new(bob(), Span::default(), vec![pay(alice(), 1)])
+        ];
+
+        for transaction in refused {
+            let error = LedgerError::InsufficientFunds { address: transaction.payer.clone(), asset: Asset::Znt };
+
+            assert_eq!(ledger.apply_all(&[transaction]), Err((0, error)));
+            assert_eq!(canonical_bytes(&ledger), before);
+        }
+    }
+
+    #[test]
+    fn transactions_are_applied_together_or_not_at_all() {
+        let mut ledger = ledger();
+        let before = canonical_bytes(&ledger);
+        let transactions = [transaction(vec![pay(bob(), 50)]), transaction(vec![pay(bob(), 20)])];
+
+        assert_eq!(
+            ledger.apply_all(&transactions),
+            Err((1, LedgerError::InsufficientFunds { address: alice(), asset: Asset::Znt }))
+        );
+        assert_eq!(canonical_bytes(&ledger), before);
+
+        ledger.apply_all(&transactions[..1]).unwrap();
+
+        assert_eq!(ledger.balance(&bob(), Asset::Znt), Decimal::from_int(50));
+    }
+
+    #[test]
+    fn ledger_refuses_to_create_an_address_twice() {
+        let mut ledger = InMemoryLedger::new();
+        let address = Address::derive(&alice(), 0);
+        let transactions = [transaction(vec![TransactionOperation::CreateAddress(address.clone())])];
+
+        ledger.apply_all(&transactions).unwrap();
+
+        assert_eq!(ledger.apply_all(&transactions), Err((0, LedgerError::AddressInUse { address })));
+        assert_eq!(ledger.address_nonce(&alice()), 1);
+    }
+}
@@ -1,21 +1,98 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
 use regex::Regex;
-use crate::grammar::{ HeapKeyword, StackKeyword, get_heap_keyword, get_stack_keyword };
+use lazy_static::lazy_static;
+use crate::address::Address;
+use crate::decimal::Decimal;
+use crate::error::LexError;
+use crate::grammar::{ HeapKeyword, StackKeyword, get_heap_keyword, get_stack_keyword, get_op_atom };
+
+lazy_static! {
+    static ref NUMBER: Regex = Regex::new(r"^[0-9]+(\.[0-9]+)?$").unwrap();
+}
 
 /// A data structure for the types of lexical tokens
 #[derive(Debug, Clone, PartialEq)]
 pub enum LexToken {
     Punc(char),
     Op(String),
-    Number(f64),
-    Value(String),
+    Number(Decimal),
+    Bool(bool),
+    Ident(String),
+    Str(String),
+    Address(Address),
     HeapKeyword(HeapKeyword),
     StackKeyword(StackKeyword)
 }
 
+/// A range of the original script. `start` and `end` are byte offsets, with `end`
+/// exclusive, while `line` and `column` locate the start and both begin at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize
+}
+
+/// A lexical token along with the range of the script it was lexed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: LexToken,
+    pub span: Span
+}
+
 /// A builder for lexical token representations
 #[derive(Debug, Clone)]
 pub struct Lexer {
-    pub tokens: Vec<LexToken>
+    pub tokens: Vec<Token>
+}
+
+impl Span {
+    /// Creates a span covering this span, the other span and everything between them
+    ///
+    /// ### Arguments
+    ///
+    /// * `other`   - The span to join with
+    pub fn join(&self, other: Span) -> Span {
+        let (first, last) = if self.start <= other.start { (*self, other) } else { (other, *self) };
+
+        Span {
+            start: first.start,
+            end: first.end.max(last.end),
+            line: first.line,
+            column: first.column
+        }
+    }
+}
+
+impl Default for Span {
+    fn default() -> Span {
+        Span { start: 0, end: 0, line: 1, column: 1 }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for LexToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexToken::Punc(p) => write!(f, "'{}'", p),
+            LexToken::Op(o) => write!(f, "'{}'", o),
+            LexToken::Number(n) => write!(f, "{}", n),
+            LexToken::Bool(b) => write!(f, "{}", b),
+            LexToken::Ident(v) => write!(f, "'{}'", v),
+            LexToken::Str(v) => write!(f, "{:?}", v),
+            LexToken::Address(a) => write!(f, "{}", a),
+            LexToken::HeapKeyword(k) => write!(f, "{}", format!("{:?}", k).to_lowercase()),
+            LexToken::StackKeyword(k) => write!(f, "{}", format!("{:?}", k).to_uppercase())
+        }
+    }
 }
 
 impl Lexer {
@@ -28,122 +105,437 @@ impl Lexer {
     }
 
     /// Lex input string into a vector of lexical tokens
-    /// 
+    ///
     /// ### Arguments
-    /// 
+    ///
     /// * `input`   - The input script to lex
-    pub fn lex(&mut self, input: &String) {
-        let input_with_breaks = self.insert_breaks(input);
-        let input_vec = input_with_breaks.chars().collect::<Vec<char>>();
-        let mut tokens = Vec::with_capacity(input_vec.len());
-        let mut current_atom = Vec::with_capacity(input_vec.len());
+    pub fn lex(&mut self, input: &str) -> Result<(), LexError> {
+        let mut chars = input.chars().peekable();
+        let mut tokens = Vec::new();
+        let mut current_atom = Vec::new();
+        let mut atom_span = Span::default();
+        let mut position = Span::default();
+
+        while let Some(character) = chars.next() {
+            let start = position;
+            position = self.advance(position, character);
+            let continues_atom = self.continues_atom(&current_atom, character);
+
+            // Any character that isn't part of a word ends the current token
+            if !continues_atom && !current_atom.is_empty() {
+                atom_span.end = start.start;
+                tokens.push(Token {
+                    kind: self.lex_token(&current_atom, atom_span)?,
+                    span: atom_span
+                });
+                current_atom.clear();
+            }
 
-        for character in input_vec {
             match character {
-                ' ' => {
-                    // Take a space character as a break in token
-                    if current_atom.len() > 0 {
-                        let next_token = self.lex_token(&current_atom);
+                '(' | ')' | '[' | ']' | '{' | '}' | ';' | ',' => {
+                    tokens.push(Token {
+                        kind: LexToken::Punc(character),
+                        span: Span { end: position.start, ..start }
+                    });
+                }
+                '*' | '-' | '+' | '/' | '^' | '<' | '>' | '=' | '!' => {
+                    let mut operator = character.to_string();
+
+                    // Check for double char operators
+                    if let Some(&next) = chars.peek() {
+                        if get_op_atom(&format!("{}{}", character, next)).is_some() {
+                            operator.push(next);
+                            position = self.advance(position, next);
+                            chars.next();
+                        }
+                    }
 
-                        tokens.push(next_token);
-                        current_atom.clear();
+                    let span = Span { end: position.start, ..start };
+
+                    if get_op_atom(&operator).is_none() {
+                        return Err(LexError::UnknownOperator { operator, span });
                     }
+
+                    tokens.push(Token { kind: LexToken::Op(operator), span });
+                }
+                '@' => {
+                    let mut literal = String::new();
+
+                    while let Some(&next) = chars.peek() {
+                        if !self.is_word_character(next) {
+                            break;
+                        }
+
+                        literal.push(next);
+                        position = self.advance(position, next);
+                        chars.next();
+                    }
+
+                    let span = Span { end: position.start, ..start };
+
+                    match Address::from_hex(&literal) {
+                        Ok(address) => tokens.push(Token { kind: LexToken::Address(address), span }),
+                        Err(error) => return Err(LexError::InvalidAddress { literal, error, span })
+                    }
+                }
+                '\'' | '"' => {
+                    let value = self.lex_string(&mut chars, character, start, &mut position)?;
+
+                    tokens.push(Token {
+                        kind: LexToken::Str(value),
+                        span: Span { end: position.start, ..start }
+                    });
+                }
+                c if c.is_whitespace() => {}
+                c if continues_atom => {
+                    if current_atom.is_empty() {
+                        atom_span = start;
+                    }
+
+                    current_atom.push(c);
                 }
                 _ => {
-                    current_atom.push(character);
+                    return Err(LexError::UnexpectedCharacter {
+                        character,
+                        span: Span { end: position.start, ..start }
+                    });
                 }
             }
         }
 
         // Assume the remaining atom is a full token
         if !current_atom.is_empty() {
-            tokens.push(self.lex_token(&current_atom));
+            atom_span.end = position.start;
+            tokens.push(Token {
+                kind: self.lex_token(&current_atom, atom_span)?,
+                span: atom_span
+            });
         }
 
         self.tokens = tokens;
+        Ok(())
     }
 
     /// Parse a single token in vector format to return a lexed output
-    /// 
+    ///
     /// ### Arguments
-    /// 
+    ///
     /// * `input`   - The set of characters representing a single token
-    fn lex_token(&self, input: &Vec<char>) -> LexToken {
+    /// * `span`    - Range of the script the token was lexed from
+    fn lex_token(&self, input: &[char], span: Span) -> Result<LexToken, LexError> {
         let input_to_check: String = input.iter().collect();
 
-        // Do a character check if it's a single character
-        if input_to_check.len() == 1 {
-            let char_to_check = input_to_check.chars().next().unwrap();
-
-            match char_to_check {
-                '*' | '-' | '+' | '/' | '^' | '<' | '>' | '=' => {
-                    return LexToken::Op(char_to_check.to_string());
-                },
-                '(' | ')' | '[' | ']' | '{' | '}' | ';' => {
-                    return LexToken::Punc(char_to_check);
-                },
-                '0'...'9' => {
-                    return LexToken::Number(char_to_check.to_digit(10).unwrap_or(0) as f64);
-                },
-                _ => {
-                    return LexToken::Value(char_to_check.to_string());
-                }
-            }
+        // Check for heap keyword
+        if let Some(heap_keyword) = get_heap_keyword(&input_to_check) {
+            return Ok(LexToken::HeapKeyword(heap_keyword));
         }
 
-        // TODO: Abstract this correctly
-
+        // Check for stack keyword
+        if let Some(stack_keyword) = get_stack_keyword(&input_to_check) {
+            return Ok(LexToken::StackKeyword(stack_keyword));
+        }
 
-        // Check for double char operators
-        if input_to_check == "==" {
-            return LexToken::Op(input_to_check);
+        // Check for word operators such as `and`, `or` and `not`
+        if get_op_atom(&input_to_check).is_some() {
+            return Ok(LexToken::Op(input_to_check));
         }
 
-        // Check for heap keyword
-        if let Some(heap_keyword) = get_heap_keyword(&input_to_check) {
-            return LexToken::HeapKeyword(heap_keyword);
+        // Check for boolean literals
+        match input_to_check.as_str() {
+            "true" => { return Ok(LexToken::Bool(true)); }
+            "false" => { return Ok(LexToken::Bool(false)); }
+            _ => {}
         }
 
-        // Check for stack keyword
-        if let Some(stack_keyword) = get_stack_keyword(&input_to_check) {
-            return LexToken::StackKeyword(stack_keyword);
+        // Check for float values and int values
+        if NUMBER.is_match(&input_to_check) {
+            return match input_to_check.parse::<Decimal>() {
+                Ok(n) => Ok(LexToken::Number(n)),
+                Err(_) => Err(LexError::InvalidNumber { literal: input_to_check, span })
+            };
         }
 
-        // TODO: Check for address
-        
-        // Check for float values and multiple character int values
-        if Regex::new(r"[0-9]+\.[0-9]+").unwrap().is_match(&input_to_check) || 
-           Regex::new(r"^[0-9]+").unwrap().is_match(&input_to_check) {
-            return LexToken::Number(input_to_check.parse::<f64>().unwrap());
+        // Identifiers can't start with a digit, so anything that does is a malformed number
+        if input[0].is_ascii_digit() {
+            return Err(LexError::InvalidNumber { literal: input_to_check, span });
         }
 
-        LexToken::Value(input_to_check)
+        Ok(LexToken::Ident(input_to_check))
     }
 
-    /// Inserts breaks in parentheses and other punctuation. This is needed 
-    /// to parse punctuation separately from script content.
-    /// 
+    /// Lex the contents of a string literal, up to and including the closing quote.
+    /// Strings may span several lines and support the escape sequences `\n`, `\r`,
+    /// `\t`, `\0`, `\\`, `\'`, `\"` and `\u{..}`.
+    ///
     /// ### Arguments
-    /// 
-    /// * `script`  - The script to insert breaks into
-    fn insert_breaks(&self, script: &String) -> String {
-        let script_chars = script.chars().collect::<Vec<char>>();
-        let mut script_with_breaks = Vec::new();
-
-        for c in script_chars {
-            match c {
-                '(' | ')' | '[' | ']' | '{' | '}' | ';' => {
-                    script_with_breaks.append(&mut vec![' ', c, ' ']);
-                },
-                '\n' => {
-                    continue;
+    ///
+    /// * `chars`       - Characters of the script, positioned after the opening quote
+    /// * `quote`       - The quote character that opened the string
+    /// * `start`       - Location of the opening quote
+    /// * `position`    - Current location in the script, moved past the closing quote
+    fn lex_string(
+        &self,
+        chars: &mut Peekable<Chars>,
+        quote: char,
+        start: Span,
+        position: &mut Span
+    ) -> Result<String, LexError> {
+        let mut value = String::new();
+
+        while let Some(character) = chars.next() {
+            let escape_start = *position;
+            *position = self.advance(*position, character);
+
+            if character == quote {
+                return Ok(value);
+            }
+
+            if character != '\\' {
+                value.push(character);
+                continue;
+            }
+
+            let escaped = match chars.next() {
+                Some(c) => c,
+                None => break
+            };
+            *position = self.advance(*position, escaped);
+
+            match escaped {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                '0' => value.push('\0'),
+                '\\' | '\'' | '"' => value.push(escaped),
+                'u' => {
+                    let mut sequence = String::from("\\u");
+
+                    // Unicode escapes take the form \u{..} with 1 to 6 hex digits
+                    for c in chars.by_ref() {
+                        *position = self.advance(*position, c);
+                        sequence.push(c);
+
+                        if c == '}' || c == quote {
+                            break;
+                        }
+                    }
+
+                    let unicode = sequence
+                        .strip_prefix("\\u{")
+                        .and_then(|rest| rest.strip_suffix('}'))
+                        .filter(|hex| !hex.is_empty() && hex.len() <= 6)
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(std::char::from_u32);
+
+                    match unicode {
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(LexError::InvalidEscape {
+                                sequence,
+                                span: Span { end: position.start, ..escape_start }
+                            });
+                        }
+                    }
                 }
                 _ => {
-                    script_with_breaks.push(c);
+                    return Err(LexError::InvalidEscape {
+                        sequence: format!("\\{}", escaped),
+                        span: Span { end: position.start, ..escape_start }
+                    });
                 }
             }
         }
 
-        script_with_breaks.into_iter().collect()
+        Err(LexError::UnterminatedString { span: Span { end: position.start, ..start } })
+    }
+
+    /// Checks whether a character can be part of a multi-character token
+    /// such as a keyword, number or value
+    ///
+    /// ### Arguments
+    ///
+    /// * `character`   - The character to check
+    fn is_word_character(&self, character: char) -> bool {
+        character.is_alphanumeric() || character == '_'
+    }
+
+    /// Checks whether a character can be added to the token being built. A `.`
+    /// is only allowed as the decimal point of a numeric literal, so it can't
+    /// start a token or appear in keywords and identifiers.
+    ///
+    /// ### Arguments
+    ///
+    /// * `atom`        - The characters of the token built so far
+    /// * `character`   - The character to check
+    fn continues_atom(&self, atom: &[char], character: char) -> bool {
+        match character {
+            '.' => matches!(atom.first(), Some(c) if c.is_ascii_digit()),
+            c => self.is_word_character(c)
+        }
+    }
+
+    /// Moves a location in the script past the given character. The location
+    /// is an empty span, so `start` and `end` always hold the same offset.
+    ///
+    /// ### Arguments
+    ///
+    /// * `position`    - The location of the character
+    /// * `character`   - The character being moved past
+    fn advance(&self, position: Span, character: char) -> Span {
+        let offset = position.start + character.len_utf8();
+
+        if character == '\n' {
+            Span { start: offset, end: offset, line: position.line + 1, column: 1 }
+        } else {
+            Span { start: offset, end: offset, line: position.line, column: position.column + 1 }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::LexError;
+    use super::{LexToken, Lexer, Span, Token};
+
+    fn lex(input: &str) -> Result<Vec<Token>, LexError> {
+        let mut lexer = Lexer::new();
+        lexer.lex(input)?;
+        Ok(lexer.tokens)
+    }
+
+    fn span(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span { start, end, line, column }
+    }
+
+    #[test]
+    fn spans_count_bytes_for_offsets_and_characters_for_columns() {
+        let tokens = lex("let café = 'ü';\nset x = 1;").unwrap();
+        let spans: Vec<Span> = tokens.iter().map(|token| token.span).collect();
+
+        assert_eq!(tokens[1].kind, LexToken::Ident(String::from("café")));
+        assert_eq!(tokens[3].kind, LexToken::Str(String::from("ü")));
+        assert_eq!(spans, vec![
+            span(0, 3, 1, 1),
+            span(4, 9, 1, 5),
+            span(10, 11, 1, 10),
+            span(12, 16, 1, 12),
+            span(16, 17, 1, 15),
+            span(18, 21, 2, 1),
+            span(22, 23, 2, 5),
+            span(24, 25, 2, 7),
+            span(26, 27, 2, 9),
+            span(27, 28, 2, 10)
+        ]);
+    }
+
+    #[test]
+    fn columns_restart_on_each_line() {
+        let tokens = lex("a\n\n  b\r\n\tc").unwrap();
+        let spans: Vec<Span> = tokens.iter().map(|token| token.span).collect();
+
+        assert_eq!(spans, vec![span(0, 1, 1, 1), span(5, 6, 3, 3), span(9, 10, 4, 2)]);
+    }
+
+    fn string(input: &str) -> Result<String, LexError> {
+        match lex(input)?.remove(0).kind {
+            LexToken::Str(value) => Ok(value),
+            other => panic!("expected a string, found {:?}", other)
+        }
+    }
+
+    #[test]
+    fn escape_sequences_are_replaced_in_strings() {
+        assert_eq!(string(r"'a\nb\tc\rd\0e'"), Ok(String::from("a\nb\tc\rd\0e")));
+        assert_eq!(string(r"'back\\slash'"), Ok(String::from("back\\slash")));
+        assert_eq!(string(r#"'\'single\' and \"double\"'"#), Ok(String::from("'single' and \"double\"")));
+        assert_eq!(string(r#""it's \"quoted\"""#), Ok(String::from("it's \"quoted\"")));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unicode_escapes_take_one_to_six_hex_digits() {
+        assert_eq!(string(r"'\u{41}\u{e9}\u{1F600}\u{10FFFF}'"), Ok(String::from("Aé😀\u{10FFFF}")));
+    }
+
+    #[test]
+    fn invalid_escape_sequences_are_rejected() {
+        let invalid = |sequence: &str, end: usize| Err(LexError::InvalidEscape {
+            sequence: String::from(sequence),
+            span: span(1, end, 1, 2)
+        });
+
+        assert_eq!(string(r"'\q'"), invalid(r"\q", 3));
+        assert_eq!(string(r"'\u{D800}'"), invalid(r"\u{D800}", 9));
+        assert_eq!(string(r"'\u{110000}'"), invalid(r"\u{110000}", 11));
+        assert_eq!(string(r"'\u{1000000}'"), invalid(r"\u{1000000}", 12));
+        assert_eq!(string(r"'\u{}'"), invalid(r"\u{}", 5));
+        assert_eq!(string(r"'\u{zz}'"), invalid(r"\u{zz}", 7));
+        assert_eq!(string(r"'\u41'"), invalid(r"\u41'", 6));
+    }
+
+    #[test]
+    fn strings_can_span_several_lines() {
+        let tokens = lex("'one\ntwo' x").unwrap();
+
+        assert_eq!(tokens, vec![
+            Token { kind: LexToken::Str(String::from("one\ntwo")), span: span(0, 9, 1, 1) },
+            Token { kind: LexToken::Ident(String::from("x")), span: span(10, 11, 2, 6) }
+        ]);
+    }
+
+    #[test]
+    fn unterminated_strings_are_rejected_from_their_opening_quote() {
+        assert_eq!(
+            lex("let a = 1;\nlet b = 'open"),
+            Err(LexError::UnterminatedString { span: span(19, 24, 2, 9) })
+        );
+        assert_eq!(
+            lex("let a = \"mismatched';"),
+            Err(LexError::UnterminatedString { span: span(8, 21, 1, 9) })
+        );
+        assert_eq!(
+            lex("let a = 'escaped quote\\'"),
+            Err(LexError::UnterminatedString { span: span(8, 24, 1, 9) })
+        );
+    }
+
+    #[test]
+    fn unknown_operators_are_rejected() {
+        assert_eq!(
+            lex("let a = 2 ^ 3;"),
+            Err(LexError::UnknownOperator { operator: String::from("^"), span: span(10, 11, 1, 11) })
+        );
+        assert_eq!(
+            lex("if (!a) {}"),
+            Err(LexError::UnknownOperator { operator: String::from("!"), span: span(4, 5, 1, 5) })
+        );
+        assert_eq!(
+            lex("let a = 1 !! 2;"),
+            Err(LexError::UnknownOperator { operator: String::from("!"), span: span(10, 11, 1, 11) })
+        );
+    }
+
+    #[test]
+    fn dots_are_only_allowed_inside_numbers() {
+        assert_eq!(lex("1.25").unwrap()[0].kind, LexToken::Number("1.25".parse().unwrap()));
+        assert_eq!(
+            lex("a.b"),
+            Err(LexError::UnexpectedCharacter { character: '.', span: span(1, 2, 1, 2) })
+        );
+        assert_eq!(
+            lex("let a = .5;"),
+            Err(LexError::UnexpectedCharacter { character: '.', span: span(8, 9, 1, 9) })
+        );
+        assert_eq!(
+            lex("let a = 1.2.3;"),
+            Err(LexError::InvalidNumber { literal: String::from("1.2.3"), span: span(8, 13, 1, 9) })
+        );
+    }
+
+    #[test]
+    fn double_character_operators_are_lexed_as_one_token() {
+        let tokens = lex("a <= b != c").unwrap();
+
+        assert_eq!(tokens[1], Token { kind: LexToken::Op(String::from("<=")), span: span(2, 4, 1, 3) });
+        assert_eq!(tokens[3], Token { kind: LexToken::Op(String::from("!=")), span: span(7, 9, 1, 8) });
+    }
+}
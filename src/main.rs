@@ -1,24 +1,48 @@
 extern crate regex;
 
+mod address;
+mod decimal;
+mod error;
 mod lexer;
 mod grammar;
 mod syntax;
 mod kernel;
 mod utils;
 mod compiler;
+mod transaction;
+mod ledger;
+mod fuel;
+mod canonical;
 
+use std::process;
+use crate::address::Address;
+use crate::decimal::{Decimal, RoundingMode};
+use crate::ledger::{InMemoryLedger, Ledger};
+use crate::transaction::Asset;
 use crate::syntax::Parser;
-use crate::compiler::Compiler;
+use crate::kernel::Kernel;
+use crate::fuel::DEFAULT_FUEL_LIMIT;
+use crate::canonical::canonical_hash;
 
 fn main() {
     let script = 
     "
-    set other_value = 3;
-    set coin = 10 / other_value;
-    set address1 = 'A0f932582395';
-    set address2 = 'B13509813fg1';
+    let other_value = 3;
+    let coin = 10 / other_value + 2 * (other_value - 1);
+    const address1 = @10b679b5050bb00a4b8f61028bac47e768e231c6c5b21c0bd8cecb5ab99ada27f751e3ee;
+    const address2 = @8b34cd35cfb44d3305e3e89cec7a6f1e74a22063404787dbd96ce9f62ca423419b603666;
 
-    stack [ PAY address1 coin ZNT ]
+    stack [ balance = GET AMOUNT ZNT WHERE WHO = address1 ]
+
+    if coin > 5 and balance < 100 {
+        stack [ PAY address1 coin ZNT AND PAY address2 1 SDL ]
+    } else {
+        stack [ PAY address2 coin ZNT ]
+    }
+
+    when block_height > 50 {
+        stack [ PAY address2 other_value ZNT ]
+    }
     "
     .to_string();
 
@@ -26,16 +50,98 @@ fn main() {
     let parser = Parser;
     let parse_result = match parser.parse_script(&script) {
         Ok(v) => v,
-        Err(e) => panic!("ERROR PARSING: {}", e)
+        Err(e) => {
+            eprintln!("ERROR PARSING: {}", e);
+            process::exit(1);
+        }
     };
 
-    println!("");
+    println!();
     println!("{:?}", parse_result);
-    println!("");
+    println!();
+
+    // Give the first address a balance for the script to read
+    let mut ledger = InMemoryLedger::new();
+    ledger.add_output(
+        Address::from_hex("10b679b5050bb00a4b8f61028bac47e768e231c6c5b21c0bd8cecb5ab99ada27f751e3ee")
+            .expect("Demo address should be valid"),
+        Asset::Znt,
+        Decimal::from_int(40)
+    ).expect("Demo balance should be valid");
+
+    // Fund the payer so the ledger accepts its payments
+    let payer = Address::from_hex("c48c2ee21d1639a1b561b98c3504d87908a0285c46c2b0938750375317a2dc27e36ca522")
+        .expect("Demo payer address should be valid");
+
+    for asset in [Asset::Znt, Asset::Sdl] {
+        ledger.add_output(payer.clone(), asset, Decimal::from_int(100)).expect("Demo balance should be valid");
+    }
 
     // Execute the script
-    let mut compiler_instance = Compiler::new();
-    compiler_instance.parse_heap_expression(&parse_result);
+    let mut kernel = Kernel::new(ledger, DEFAULT_FUEL_LIMIT);
+    kernel.set_payer(payer.clone());
+    kernel.set_mintable_assets(vec![Asset::Znt]);
+
+    // Preview what the script would do under each rounding mode before executing it for real
+    for rounding in [RoundingMode::Down, RoundingMode::Up, RoundingMode::HalfUp, RoundingMode::HalfEven] {
+        kernel.set_rounding(rounding);
+
+        match kernel.simulate(&script, kernel.ledger()) {
+            Ok(v) => {
+                println!("SIMULATED WITH {:?} ROUNDING: {:?}", rounding, v.transactions);
+                println!("SIMULATED CHANGES: {:?}", v.diff);
+                println!("SIMULATED FUEL USED: {}", v.fuel_used);
+            }
+            Err(e) => {
+                eprintln!("ERROR SIMULATING: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let execution = match kernel.execute(&script) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("ERROR EXECUTING: {} after using {} fuel", e, kernel.fuel_used());
+            process::exit(1);
+        }
+    };
+
+    println!("ASSIGNMENTS: {:?}", kernel.globals());
+    println!("TRANSACTIONS: {:?}", execution.transactions);
+    println!("FUEL USED: {}", execution.fuel_used);
+    println!("PAYER OUTPUTS: {:?}", kernel.ledger().unspent_outputs(&payer));
+
+    // Make a further payment in a checkpoint, then undo it as a host would if
+    // the block holding it were abandoned
+    kernel.checkpoint();
+
+    if let Err(e) = kernel.execute("stack [ PAY address2 5 ZNT ]") {
+        eprintln!("ERROR EXECUTING: {} after using {} fuel", e, kernel.fuel_used());
+        process::exit(1);
+    }
+
+    println!("CHECKPOINTS OPEN: {}", kernel.checkpoint_depth());
+    println!("BALANCE BEFORE ROLLBACK: {}", kernel.ledger().balance(&payer, Asset::Znt));
+    kernel.rollback();
+    println!("BALANCE AFTER ROLLBACK: {}", kernel.ledger().balance(&payer, Asset::Znt));
+
+    // Move the chain on so any pending triggers are evaluated, this time
+    // keeping what they do once the block is settled
+    kernel.ledger_mut().set_block_height(100);
+    kernel.checkpoint();
+
+    match kernel.notify_state_change() {
+        Ok(v) => println!("TRIGGERED TRANSACTIONS: {:?}", v.transactions),
+        Err(e) => {
+            eprintln!("ERROR EXECUTING TRIGGERS: {}", e);
+            process::exit(1);
+        }
+    }
+
+    kernel.commit();
 
-    println!("ASSIGNMENTS: {:?}", compiler_instance.0.assignments);
+    // Every node running the same scripts against the same ledger ends up with this hash
+    let state_hash: String = canonical_hash(&kernel).iter().map(|b| format!("{:02x}", b)).collect();
+    println!("STATE HASH: {}", state_hash);
 }
@@ -1,25 +1,222 @@
 #![allow(dead_code)]
 
-use crate::grammar::{GrammarAtom, HeapKeyword, OpAtom, find_op_grammar_atom};
-use crate::lexer::{LexToken, Lexer};
+use std::collections::HashMap;
+use crate::address::Address;
+use crate::decimal::Decimal;
+use crate::error::{ParseError, SemanticError, ZqlError};
+use crate::grammar::{
+    HeapKeyword, OpAtom, StackKeyword, BUILTIN_VARIABLES, MAX_NESTING_DEPTH, find_op_atom,
+    get_not_operand_precedence, get_op_precedence,
+};
+use crate::lexer::{LexToken, Lexer, Span, Token};
+use crate::transaction::{Asset, DataEncoding};
 
-/// A representation of a token node in a syntax tree
-#[derive(Debug, Clone)]
-pub struct ParseNode {
-    pub entry: GrammarAtom,
-    pub children: Vec<ParseNode>,
+/// A sequence of Heap statements, either a full script or the contents
+/// of a block enclosed in `{` and `}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub statements: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// A single Heap statement. Each statement begins with a heap keyword.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    /// `set name = value;`, which updates a variable that is already declared
+    Set { name: String, value: Expr, span: Span },
+    /// `let name = value;`, which declares a new variable
+    Let { name: String, value: Expr, span: Span },
+    /// `const name = value;`, which declares a variable that can't be updated
+    Const { name: String, value: Expr, span: Span },
+    /// `if condition { ... } else { ... }`, where an `else if` becomes an
+    /// else block holding a single nested conditional
+    If { condition: Expr, then_block: Block, else_block: Option<Block>, span: Span },
+    /// `while condition { ... }`
+    While { condition: Expr, block: Block, span: Span },
+    /// `for variable in list { ... }`
+    For { variable: String, list: Expr, block: Block, span: Span },
+    /// `when condition { ... }`
+    When { condition: Expr, block: Block, span: Span },
+    /// `stack [ ... ]`
+    Stack { statement: StackStmt, span: Span },
+}
+
+/// A single Stack statement, without its enclosing brackets
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackStmt {
+    /// `PAY payee amount asset`, optionally chained with further payments
+    /// as `... AND PAY payee amount asset`
+    Pay { payments: Vec<Payment>, span: Span },
+    /// `target = GET ...`, which stores the result of a ledger query in a
+    /// Heap variable
+    Get { target: String, query: Query, span: Span },
+    /// `CREATE key content ENCODING encoding`
+    Create { key: Expr, content: Expr, encoding: DataEncoding, span: Span },
+    /// `UPDATE key content ENCODING encoding WHERE ...`
+    Update { key: Expr, content: Expr, encoding: DataEncoding, predicates: Vec<Predicate>, span: Span },
+    /// `DELETE key WHERE ...`
+    Delete { key: Expr, predicates: Vec<Predicate>, span: Span },
+    /// `NEW asset amount TO payee`
+    Mint { payee: Expr, amount: Expr, asset: Asset, span: Span },
+    /// `target = NEW ADDRESS`
+    NewAddress { target: String, span: Span },
+    /// `TRANSACT [ ...; ... ]`, whose statements succeed or fail together
+    Transact { statements: Vec<StackStmt>, span: Span },
+}
+
+/// A single payment of a PAY statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub payee: Expr,
+    pub amount: Expr,
+    pub asset: Asset,
+    pub span: Span,
+}
+
+/// A read query against the ledger, of the form
+/// `GET <field> [asset] WHERE <predicate> AND <predicate> ...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub field: QueryField,
+    pub asset: Option<Asset>,
+    pub predicates: Vec<Predicate>,
+    pub span: Span,
+}
+
+/// What a query reads from the ledger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryField {
+    /// `AMOUNT`, the total balance of the matching addresses
+    Amount,
+    /// `TRANSACT`, the amounts of the matching transfers in the history of
+    /// the matching addresses
+    Transact,
+}
+
+/// A condition in the WHERE clause of a query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    /// `WHO = address`
+    WhoIs { address: Expr, span: Span },
+    /// `WHO IN list`
+    WhoIn { addresses: Expr, span: Span },
+    /// `AMOUNT <comparison> value`
+    Amount { op: OpAtom, value: Expr, span: Span },
+}
+
+/// An expression producing a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number { value: Decimal, span: Span },
+    Bool { value: bool, span: Span },
+    Text { value: String, span: Span },
+    Address { value: Address, span: Span },
+    Variable { name: String, span: Span },
+    List { items: Vec<Expr>, span: Span },
+    Unary { op: OpAtom, operand: Box<Expr>, span: Span },
+    Binary { op: OpAtom, left: Box<Expr>, right: Box<Expr>, span: Span },
+}
+
+/// How a name was declared, as tracked while checking declarations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Declaration {
+    Variable,
+    Constant,
 }
 
 /// A builder for parsing lexed language
 #[derive(Debug, Clone)]
 pub struct Parser;
 
-impl ParseNode {
-    /// Creates a new node for parsing
-    pub fn new() -> ParseNode {
-        ParseNode {
-            entry: GrammarAtom::Value(String::from("")),
-            children: Vec::new(),
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl Stmt {
+    /// Gets the range of the script the statement was parsed from,
+    /// including its keyword
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Set { span, .. } |
+            Stmt::Let { span, .. } |
+            Stmt::Const { span, .. } |
+            Stmt::If { span, .. } |
+            Stmt::While { span, .. } |
+            Stmt::For { span, .. } |
+            Stmt::When { span, .. } |
+            Stmt::Stack { span, .. } => *span,
+        }
+    }
+}
+
+impl StackStmt {
+    /// Gets the range of the script the statement was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            StackStmt::Pay { span, .. } |
+            StackStmt::Get { span, .. } |
+            StackStmt::Create { span, .. } |
+            StackStmt::Update { span, .. } |
+            StackStmt::Delete { span, .. } |
+            StackStmt::Mint { span, .. } |
+            StackStmt::NewAddress { span, .. } |
+            StackStmt::Transact { span, .. } => *span,
+        }
+    }
+}
+
+impl Predicate {
+    /// Gets the range of the script the predicate was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            Predicate::WhoIs { span, .. } |
+            Predicate::WhoIn { span, .. } |
+            Predicate::Amount { span, .. } => *span,
+        }
+    }
+}
+
+impl Expr {
+    /// Gets the range of the script the expression was parsed from.
+    /// Parentheses around an expression are included in its span.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Number { span, .. } |
+            Expr::Bool { span, .. } |
+            Expr::Text { span, .. } |
+            Expr::Address { span, .. } |
+            Expr::Variable { span, .. } |
+            Expr::List { span, .. } |
+            Expr::Unary { span, .. } |
+            Expr::Binary { span, .. } => *span,
+        }
+    }
+
+    /// Gets the number of levels the expression is nested to, where a single
+    /// value is one level deep
+    pub fn depth(&self) -> usize {
+        match self {
+            Expr::List { items, .. } => 1 + items.iter().map(Expr::depth).max().unwrap_or(0),
+            Expr::Unary { operand, .. } => 1 + operand.depth(),
+            Expr::Binary { left, right, .. } => 1 + left.depth().max(right.depth()),
+            _ => 1,
+        }
+    }
+
+    /// Gets a copy of the expression covering a different range of the script
+    ///
+    /// ### Arguments
+    ///
+    /// * `new_span`    - The range the copy should cover
+    fn with_span(self, new_span: Span) -> Expr {
+        match self {
+            Expr::Number { value, .. } => Expr::Number { value, span: new_span },
+            Expr::Bool { value, .. } => Expr::Bool { value, span: new_span },
+            Expr::Text { value, .. } => Expr::Text { value, span: new_span },
+            Expr::Address { value, .. } => Expr::Address { value, span: new_span },
+            Expr::Variable { name, .. } => Expr::Variable { name, span: new_span },
+            Expr::List { items, .. } => Expr::List { items, span: new_span },
+            Expr::Unary { op, operand, .. } => Expr::Unary { op, operand, span: new_span },
+            Expr::Binary { op, left, right, .. } => Expr::Binary { op, left, right, span: new_span },
         }
     }
 }
@@ -30,254 +227,1583 @@ impl Parser {
     /// ### Arguments
     ///
     /// * `input`   - The input script to parse
-    pub fn parse_script(&self, input: &String) -> Result<(ParseNode), String> {
+    pub fn parse_script(&self, input: &str) -> Result<Block, ZqlError> {
+        self.parse_script_with_globals(input, &[])
+    }
+
+    /// Parses a full script that may use the global variables declared by
+    /// scripts run before it
+    ///
+    /// ### Arguments
+    ///
+    /// * `input`   - The input script to parse
+    /// * `globals` - Names of the global variables already declared, each with
+    ///   whether it is a constant
+    pub fn parse_script_with_globals(&self, input: &str, globals: &[(String, bool)]) -> Result<Block, ZqlError> {
         let mut lex_inst = Lexer::new();
-        lex_inst.lex(input);
+        lex_inst.lex(input)?;
 
         let tokens = lex_inst.tokens;
         let token_len = tokens.len();
 
         let mut position = 0;
-        let mut syntax_tree = ParseNode::new();
-        syntax_tree.entry = GrammarAtom::HeapExpression;
+        let mut statements = Vec::new();
 
         while position < token_len {
-            let c: &LexToken = tokens
-                .get(position)
-                .ok_or(
-                    String::from("Tried to access a lexical token, but the index requested doesn't exist in the list")
-                )?;
-
-            match c {
-                LexToken::HeapKeyword(k) => {
-                    let (node, next_position) = if k == &HeapKeyword::Stack {
-                        self.parse_stack_expression(&tokens, position + 1)?
-                    } else {
-                        self.parse_heap_expression(&tokens, position + 1)?
+            let (statement, next_position) = self.parse_statement(&tokens, position, 0)?;
+
+            statements.push(statement);
+            position = next_position;
+        }
+
+        let script = Block { statements, span: self.get_range_span(&tokens, 0, token_len) };
+        let mut declared = BUILTIN_VARIABLES
+            .iter()
+            .map(|name| (name.to_string(), Declaration::Constant))
+            .collect::<HashMap<String, Declaration>>();
+
+        for (name, constant) in globals {
+            let declaration = if *constant { Declaration::Constant } else { Declaration::Variable };
+            declared.insert(name.clone(), declaration);
+        }
+
+        self.check_block_declarations(&script, &mut declared)?;
+
+        Ok(script)
+    }
+
+    /// Checks that every variable used in a block was declared earlier in the
+    /// script, in the block itself or in a block enclosing it. Statements are
+    /// visited in script order, so a LET only declares its variable after its
+    /// own right hand side has been checked. Variables declared in a nested
+    /// block are dropped when the block ends, so they are checked against a
+    /// copy of the declarations. SET can only update a variable that is
+    /// already declared, constants can't be updated at all, and neither LET,
+    /// CONST nor the variable of a FOR loop can declare a name that is already
+    /// in scope.
+    ///
+    /// ### Arguments
+    ///
+    /// * `block`               - Block to check
+    /// * `declared`            - Names of the variables declared so far
+    fn check_block_declarations(
+        &self,
+        block: &Block,
+        declared: &mut HashMap<String, Declaration>,
+    ) -> Result<(), ZqlError> {
+        for statement in &block.statements {
+            match statement {
+                Stmt::Set { name, value, span } => {
+                    self.check_expression_declarations(value, declared)?;
+
+                    if !declared.contains_key(name) {
+                        return Err(SemanticError::UndeclaredVariable {
+                            name: name.clone(),
+                            span: *span,
+                        }.into());
+                    }
+
+                    self.check_assignment_target(name, *span, declared)?;
+                }
+                Stmt::Let { name, value, span } | Stmt::Const { name, value, span } => {
+                    self.check_expression_declarations(value, declared)?;
+
+                    if declared.contains_key(name) {
+                        return Err(SemanticError::AlreadyDeclared {
+                            name: name.clone(),
+                            span: *span,
+                        }.into());
+                    }
+
+                    let declaration = match statement {
+                        Stmt::Const { .. } => Declaration::Constant,
+                        _ => Declaration::Variable,
                     };
 
-                    let mut heap_keyword = ParseNode::new();
-                    heap_keyword.entry = GrammarAtom::HeapKeyword(k.clone());
-                    heap_keyword.children.push(node);
+                    declared.insert(name.clone(), declaration);
+                }
+                Stmt::If { condition, then_block, else_block, .. } => {
+                    self.check_expression_declarations(condition, declared)?;
+                    self.check_block_declarations(then_block, &mut declared.clone())?;
+
+                    if let Some(block) = else_block {
+                        self.check_block_declarations(block, &mut declared.clone())?;
+                    }
+                }
+                Stmt::While { condition, block, .. } | Stmt::When { condition, block, .. } => {
+                    self.check_expression_declarations(condition, declared)?;
+                    self.check_block_declarations(block, &mut declared.clone())?;
+                }
+                Stmt::For { variable, list, block, span } => {
+                    self.check_expression_declarations(list, declared)?;
 
-                    syntax_tree.children.push(heap_keyword);
+                    if declared.contains_key(variable) {
+                        return Err(SemanticError::AlreadyDeclared {
+                            name: variable.clone(),
+                            span: *span,
+                        }.into());
+                    }
 
-                    position = next_position;
+                    let mut block_declared = declared.clone();
+                    block_declared.insert(variable.clone(), Declaration::Variable);
+                    self.check_block_declarations(block, &mut block_declared)?;
                 }
-                LexToken::StackKeyword(_k) => {
-                    return Err(format!(
-                        "The command or value {:?} is a Stack keyword and needs to be declared in a Stack",
-                        { c }
-                    ));
+                Stmt::Stack { statement, .. } => {
+                    self.check_stack_declarations(statement, declared)?;
                 }
-                _ => {
-                    return Err(format!(
-                        "The command or value {:?} at position {} is syntactically invalid",
-                        { c },
-                        position
-                    ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every variable a Stack statement uses has been declared, and
+    /// declares the variable a GET assigns to
+    ///
+    /// ### Arguments
+    ///
+    /// * `statement`           - Stack statement to check
+    /// * `declared`            - Names of the variables declared so far
+    fn check_stack_declarations(
+        &self,
+        statement: &StackStmt,
+        declared: &mut HashMap<String, Declaration>,
+    ) -> Result<(), ZqlError> {
+        match statement {
+            StackStmt::Pay { payments, .. } => {
+                for payment in payments {
+                    self.check_expression_declarations(&payment.payee, declared)?;
+                    self.check_expression_declarations(&payment.amount, declared)?;
+                }
+            }
+            StackStmt::Get { target, query, span } => {
+                self.check_predicate_declarations(&query.predicates, declared)?;
+                self.check_assignment_target(target, *span, declared)?;
+            }
+            StackStmt::Create { key, content, .. } => {
+                self.check_expression_declarations(key, declared)?;
+                self.check_expression_declarations(content, declared)?;
+            }
+            StackStmt::Update { key, content, predicates, .. } => {
+                self.check_expression_declarations(key, declared)?;
+                self.check_expression_declarations(content, declared)?;
+                self.check_predicate_declarations(predicates, declared)?;
+            }
+            StackStmt::Delete { key, predicates, .. } => {
+                self.check_expression_declarations(key, declared)?;
+                self.check_predicate_declarations(predicates, declared)?;
+            }
+            StackStmt::Mint { payee, amount, .. } => {
+                self.check_expression_declarations(payee, declared)?;
+                self.check_expression_declarations(amount, declared)?;
+            }
+            StackStmt::NewAddress { target, span } => {
+                self.check_assignment_target(target, *span, declared)?;
+            }
+            StackStmt::Transact { statements, .. } => {
+                for statement in statements {
+                    self.check_stack_declarations(statement, declared)?;
                 }
             }
         }
 
-        Ok(syntax_tree)
+        Ok(())
     }
 
-    /// Generic parse method for expressions
+    /// Checks that a variable can be assigned to, declaring it if it hasn't
+    /// been declared yet
     ///
     /// ### Arguments
     ///
-    /// * `tokens`              - Tokens of the expression to parse
-    /// * `position`            - Index position to parse
-    fn parse_expression(
+    /// * `name`                - Name of the variable assigned to
+    /// * `span`                - Location of the assignment in the script
+    /// * `declared`            - Names of the variables declared so far
+    fn check_assignment_target(
         &self,
-        tokens: &Vec<LexToken>,
-        position: usize,
-    ) -> Result<(ParseNode, usize), String> {
-        let mut node = ParseNode::new();
-        let c: &LexToken = tokens.get(position).ok_or(String::from(
-            "Tried to access a lexical token, but the index requested doesn't exist in the list",
-        ))?;
+        name: &str,
+        span: Span,
+        declared: &mut HashMap<String, Declaration>,
+    ) -> Result<(), ZqlError> {
+        if declared.get(name) == Some(&Declaration::Constant) {
+            return Err(SemanticError::ConstantReassignment {
+                name: name.to_string(),
+                span,
+            }.into());
+        }
+
+        declared.insert(name.to_string(), Declaration::Variable);
+        Ok(())
+    }
 
-        match c {
-            LexToken::Number(n) => {
-                node.entry = GrammarAtom::Number(n.clone());
+    /// Checks that every variable used in the predicates of a WHERE clause has been declared
+    ///
+    /// ### Arguments
+    ///
+    /// * `predicates`          - Predicates to check
+    /// * `declared`            - Names of the variables declared so far
+    fn check_predicate_declarations(
+        &self,
+        predicates: &[Predicate],
+        declared: &HashMap<String, Declaration>,
+    ) -> Result<(), ZqlError> {
+        for predicate in predicates {
+            match predicate {
+                Predicate::WhoIs { address: value, .. } |
+                Predicate::WhoIn { addresses: value, .. } |
+                Predicate::Amount { value, .. } => {
+                    self.check_expression_declarations(value, declared)?;
+                }
             }
-            LexToken::StackKeyword(k) => {
-                node.entry = GrammarAtom::StackKeyword(k.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every variable used in an expression has been declared
+    ///
+    /// ### Arguments
+    ///
+    /// * `expression`          - Expression to check
+    /// * `declared`            - Names of the variables declared so far
+    fn check_expression_declarations(
+        &self,
+        expression: &Expr,
+        declared: &HashMap<String, Declaration>,
+    ) -> Result<(), ZqlError> {
+        match expression {
+            Expr::Variable { name, span } if !declared.contains_key(name) => {
+                return Err(SemanticError::UndeclaredVariable {
+                    name: name.clone(),
+                    span: *span,
+                }.into());
             }
-            LexToken::Value(v) => {
-                node.entry = GrammarAtom::Value(v.clone());
+            Expr::List { items, .. } => {
+                for item in items {
+                    self.check_expression_declarations(item, declared)?;
+                }
             }
-            LexToken::HeapKeyword(k) => {
-                node.entry = GrammarAtom::HeapKeyword(k.clone());
+            Expr::Unary { operand, .. } => {
+                self.check_expression_declarations(operand, declared)?;
             }
-            _ => {
-                return Err(format!("The command or value {:?} is not valid in ZQL", {
-                    c
-                }));
+            Expr::Binary { left, right, .. } => {
+                self.check_expression_declarations(left, declared)?;
+                self.check_expression_declarations(right, declared)?;
             }
-        };
+            _ => {}
+        }
 
-        Ok((node, position + 1))
+        Ok(())
     }
 
-    /// Parses a heap expression
+    /// Checks that parsing can go one level deeper without passing the
+    /// deepest nesting allowed
     ///
     /// ### Arguments
     ///
-    /// * `tokens`              - Tokens of the expression to parse
-    /// * `position`            - Index position to parse
-    fn parse_heap_expression(
+    /// * `depth`               - Levels of nesting already entered
+    /// * `span`                - Location of the nested expression or block
+    fn check_depth(&self, depth: usize, span: Span) -> Result<(), ParseError> {
+        if depth >= MAX_NESTING_DEPTH {
+            return Err(ParseError::NestingTooDeep {
+                limit: MAX_NESTING_DEPTH,
+                span,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that an expression doesn't pass the deepest nesting allowed,
+    /// counting the levels of nesting it is found within. Operators chained
+    /// without parentheses nest just as deeply as parenthesised ones.
+    ///
+    /// ### Arguments
+    ///
+    /// * `expression`          - Expression to check
+    /// * `depth`               - Levels of nesting the expression is found within
+    fn check_expression_depth(&self, expression: Expr, depth: usize) -> Result<Expr, ParseError> {
+        if depth + expression.depth() > MAX_NESTING_DEPTH {
+            return Err(ParseError::NestingTooDeep {
+                limit: MAX_NESTING_DEPTH,
+                span: expression.span(),
+            });
+        }
+
+        Ok(expression)
+    }
+
+    /// Gets the span covering every token in the range `start..end`. An empty
+    /// range gives an empty span at the start of the script.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `start`               - Index position of the first token in the range
+    /// * `end`                 - Index position directly after the last token in the range
+    fn get_range_span(&self, tokens: &[Token], start: usize, end: usize) -> Span {
+        match (tokens.get(start), end.checked_sub(1).and_then(|last| tokens.get(last))) {
+            (Some(first), Some(last)) if start < end => first.span.join(last.span),
+            _ => Span::default(),
+        }
+    }
+
+    /// Gets the token at the given position, failing with a description of
+    /// what was expected if the script has already ended
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the token to get
+    /// * `expected`            - Description of the token expected at this position
+    fn get_token<'a>(
         &self,
-        tokens: &Vec<LexToken>,
+        tokens: &'a [Token],
         position: usize,
-    ) -> Result<(ParseNode, usize), String> {
-        let mut heap_expression = ParseNode::new();
-        let mut mut_position = position;
+        expected: &str,
+    ) -> Result<&'a Token, ParseError> {
+        tokens.get(position).ok_or_else(|| ParseError::UnexpectedEnd {
+            expected: expected.to_string(),
+            span: tokens.last().map(|t| t.span).unwrap_or_default(),
+        })
+    }
+
+    /// Checks that the token at the given position is the expected punctuation
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the punctuation
+    /// * `punctuation`         - The punctuation expected
+    /// * `expected`            - Description of the punctuation for error messages
+    fn expect_punctuation(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        punctuation: char,
+        expected: &str,
+    ) -> Result<(), ParseError> {
+        let c = self.get_token(tokens, position, expected)?;
 
-        heap_expression.entry = GrammarAtom::HeapExpression;
+        match &c.kind {
+            LexToken::Punc(p) if *p == punctuation => Ok(()),
+            _ => Err(ParseError::Expected {
+                expected: expected.to_string(),
+                found: c.kind.to_string(),
+                span: c.span,
+            }),
+        }
+    }
 
-        while mut_position < tokens.len() {
-            let c: &LexToken = tokens.get(mut_position).ok_or(format!(
-                "Tried to access a lexical token at position {} in a HEAP EXPR, but the index requested doesn't exist in the list", mut_position
-            ))?;
+    /// Parses a variable name, as assigned by SET or FOR
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the name
+    /// * `expected`            - Description of the name for error messages
+    fn parse_name(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        expected: &str,
+    ) -> Result<(String, usize), ParseError> {
+        let c = self.get_token(tokens, position, expected)?;
 
-            match c {
-                LexToken::StackKeyword(k) => {
-                    return Err(format!(
-                        "INVALID: The command or value {:?} at position {} is a Stack keyword, but is being used in a ZQL Heap",
-                        { k },
-                        mut_position
-                    ));
-                }
-                LexToken::Punc(p) => {
-                    let mut node = ParseNode::new();
-                    node.entry = GrammarAtom::Punc(*p);
-
-                    // TODO: Handle bracket punctuation for child expressions
-                    match p {
-                        &'{' | &'}' | &'[' | &']' | &'(' | &')' | &';' => {
-                            mut_position += 1;
-
-                            if p == &';' {
-                                break;
-                            }
-                        }
-                        _ => {
-                            return Err(format!(
-                                "INVALID: The syntax {:?} at position {} is invalid syntax in a Heap",
-                                { p },
-                                mut_position
-                            ));
-                        }
-                    };
+        match &c.kind {
+            LexToken::Ident(name) => Ok((name.clone(), position + 1)),
+            _ => Err(ParseError::Expected {
+                expected: expected.to_string(),
+                found: c.kind.to_string(),
+                span: c.span,
+            }),
+        }
+    }
 
-                    heap_expression.children.push(node);
-                }
-                LexToken::HeapKeyword(k) => {
-                    let (node, next_position) = if k == &HeapKeyword::Stack {
-                        self.parse_stack_expression(&tokens, mut_position + 1)?
-                    } else {
-                        self.parse_heap_expression(&tokens, mut_position + 1)?
-                    };
+    /// Parses a single statement. Every statement in a Heap begins with a heap keyword.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the statement's keyword
+    /// * `depth`               - Levels of blocks the statement is nested within
+    fn parse_statement(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Stmt, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "a Heap keyword")?;
 
-                    let mut heap_keyword = ParseNode::new();
-                    heap_keyword.entry = GrammarAtom::HeapKeyword(k.clone());
-                    heap_keyword.children.push(node);
-                    heap_expression.children.push(heap_keyword);
+        match &c.kind {
+            LexToken::HeapKeyword(HeapKeyword::Stack) => self.parse_stack_statement(tokens, position),
+            LexToken::HeapKeyword(HeapKeyword::Set) => self.parse_assignment(tokens, position),
+            LexToken::HeapKeyword(HeapKeyword::Let) => self.parse_assignment(tokens, position),
+            LexToken::HeapKeyword(HeapKeyword::Const) => self.parse_assignment(tokens, position),
+            LexToken::HeapKeyword(HeapKeyword::If) => self.parse_conditional(tokens, position, depth),
+            LexToken::HeapKeyword(HeapKeyword::For) => self.parse_for_loop(tokens, position, depth),
+            LexToken::HeapKeyword(HeapKeyword::While) => {
+                let (condition, block, next_position) = self.parse_guarded_block(tokens, position + 1, depth)?;
+                let span = self.get_range_span(tokens, position, next_position);
 
-                    mut_position = next_position;
-                }
-                LexToken::Op(o) => {
-                    let node = find_op_grammar_atom(o);
-                    
-                    mut_position += 1;
-                    heap_expression.children.push(node);
-                }
-                _ => {
-                    // Assume anything else can be generically parsed
-                    // Errors and invalid tokens will be caught in the generic parsing method
-                    let (node, next_position) = self.parse_expression(tokens, mut_position)?;
-                    heap_expression.children.push(node);
-                    mut_position = next_position;
+                Ok((Stmt::While { condition, block, span }, next_position))
+            }
+            LexToken::HeapKeyword(HeapKeyword::When) => {
+                let (condition, block, next_position) = self.parse_guarded_block(tokens, position + 1, depth)?;
+                let span = self.get_range_span(tokens, position, next_position);
+
+                Ok((Stmt::When { condition, block, span }, next_position))
+            }
+            LexToken::StackKeyword(_k) => Err(ParseError::StackKeywordInHeap {
+                keyword: c.kind.to_string(),
+                span: c.span,
+            }.into()),
+            // An else is only valid directly after the block of an if
+            _ => Err(ParseError::UnexpectedToken {
+                found: c.kind.to_string(),
+                span: c.span,
+            }.into()),
+        }
+    }
+
+    /// Parses a block of statements enclosed in `{` and `}`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the opening `{`
+    /// * `depth`               - Levels of blocks the block is nested within
+    fn parse_block(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Block, usize), ZqlError> {
+        self.expect_punctuation(tokens, position, '{', "'{' to open a block")?;
+        self.check_depth(depth, tokens[position].span)?;
+
+        let mut statements = Vec::new();
+        let mut mut_position = position + 1;
+
+        while self.get_token(tokens, mut_position, "'}' to close the block")?.kind != LexToken::Punc('}') {
+            let (statement, next_position) = self.parse_statement(tokens, mut_position, depth + 1)?;
+
+            statements.push(statement);
+            mut_position = next_position;
+        }
+
+        let span = self.get_range_span(tokens, position, mut_position + 1);
+
+        Ok((Block { statements, span }, mut_position + 1))
+    }
+
+    /// Parses a conditional of the form `if condition { ... }`, optionally followed
+    /// by `else { ... }` or `else if ...`. An `else if` becomes an else block
+    /// holding a single nested conditional.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the IF keyword
+    /// * `depth`               - Levels of blocks the conditional is nested within
+    fn parse_conditional(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Stmt, usize), ZqlError> {
+        let (condition, next_position) = self.parse_binary_expression(tokens, position + 1, 0, 0)?;
+        let (then_block, mut mut_position) = self.parse_block(tokens, next_position, depth)?;
+        let mut else_block = None;
+
+        if let Some(LexToken::HeapKeyword(HeapKeyword::Else)) = tokens.get(mut_position).map(|t| &t.kind) {
+            let c = self.get_token(tokens, mut_position + 1, "'{' or 'if' after 'else'")?;
+
+            let (block, next_position) = match &c.kind {
+                LexToken::HeapKeyword(HeapKeyword::If) => {
+                    self.check_depth(depth, c.span)?;
+                    let (nested, next_position) = self.parse_conditional(tokens, mut_position + 1, depth + 1)?;
+                    let span = nested.span();
+
+                    (Block { statements: vec![nested], span }, next_position)
                 }
+                _ => self.parse_block(tokens, mut_position + 1, depth)?,
             };
+
+            else_block = Some(block);
+            mut_position = next_position;
+        }
+
+        let span = self.get_range_span(tokens, position, mut_position);
+
+        Ok((Stmt::If { condition, then_block, else_block, span }, mut_position))
+    }
+
+    /// Parses a guarded block of the form `condition { ... }`, as used by WHILE
+    /// loops and WHEN triggers
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position directly after the WHILE or WHEN keyword
+    /// * `depth`               - Levels of blocks the statement is nested within
+    fn parse_guarded_block(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Expr, Block, usize), ZqlError> {
+        let (condition, next_position) = self.parse_binary_expression(tokens, position, 0, 0)?;
+        let (block, next_position) = self.parse_block(tokens, next_position, depth)?;
+
+        Ok((condition, block, next_position))
+    }
+
+    /// Parses a loop of the form `for name in list { ... }`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the FOR keyword
+    /// * `depth`               - Levels of blocks the loop is nested within
+    fn parse_for_loop(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Stmt, usize), ZqlError> {
+        let (variable, next_position) = self.parse_name(tokens, position + 1, "a loop variable name")?;
+        let c = self.get_token(tokens, next_position, "'in'")?;
+
+        if c.kind != LexToken::HeapKeyword(HeapKeyword::In) {
+            return Err(ParseError::Expected {
+                expected: String::from("'in'"),
+                found: c.kind.to_string(),
+                span: c.span,
+            }.into());
         }
 
-        Ok((heap_expression, mut_position))
+        let (list, next_position) = self.parse_binary_expression(tokens, next_position + 1, 0, 0)?;
+        let (block, next_position) = self.parse_block(tokens, next_position, depth)?;
+        let span = self.get_range_span(tokens, position, next_position);
+
+        Ok((Stmt::For { variable, list, block, span }, next_position))
     }
 
-    /// Parses a stack expression. Stack expressions cannot nest, so a simple
-    /// check on the closing bracket is enough to conclude the parsing.
+    /// Parses a list literal of the form `[a, b, c]`
     ///
     /// ### Arguments
     ///
     /// * `tokens`              - Tokens of the expression to parse
-    /// * `position`            - Index position
-    fn parse_stack_expression(
+    /// * `position`            - Index position of the opening `[`
+    /// * `depth`               - Levels of nesting the list is found within
+    fn parse_list(
         &self,
-        tokens: &Vec<LexToken>,
+        tokens: &[Token],
         position: usize,
-    ) -> Result<(ParseNode, usize), String> {
-        let mut stack_expression = ParseNode::new();
-        let mut mut_position = position;
+        depth: usize,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let mut items = Vec::new();
+        let mut mut_position = position + 1;
 
-        stack_expression.entry = GrammarAtom::StackExpression;
+        if self.get_token(tokens, mut_position, "']' to close the list")?.kind != LexToken::Punc(']') {
+            loop {
+                let (item, next_position) = self.parse_binary_expression(tokens, mut_position, 0, depth + 1)?;
+                items.push(item);
 
-        loop {
-            let c: &LexToken = tokens.get(mut_position).ok_or(String::from(
-                "Tried to access a lexical token in a STACK EXPR, but the index requested doesn't exist in the list",
-            ))?;
-
-            match c {
-                LexToken::HeapKeyword(k) => {
-                    return Err(format!(
-                        "INVALID: The command or value {:?} at position {} is a Heap keyword, but is being used in a ZQL Stack",
-                        { k },
-                        mut_position
-                    ));
-                }
-                LexToken::Punc(p) => {
-                    match p {
-                        '[' | ']' => {
-                            let mut node = ParseNode::new();
-                            node.entry = GrammarAtom::Punc(*p);
-                            stack_expression.children.push(node);
-                            mut_position += 1;
-
-                            // Stack expression is considered complete
-                            if p == &']' {
-                                break;
-                            }
-                        }
-                        _ => {
-                            return Err(format!(
-                                "INVALID: '[' and ']' are the only valid brackets in a Stack. The syntax {:?} at position {} is invalid",
-                                { p },
-                                mut_position
-                            ));
-                        }
+                let c = self.get_token(tokens, next_position, "',' or ']' in the list")?;
+
+                match &c.kind {
+                    LexToken::Punc(',') => mut_position = next_position + 1,
+                    LexToken::Punc(']') => {
+                        mut_position = next_position;
+                        break;
+                    }
+                    _ => {
+                        return Err(ParseError::Expected {
+                            expected: String::from("',' or ']' in the list"),
+                            found: c.kind.to_string(),
+                            span: c.span,
+                        }.into());
                     }
                 }
-                LexToken::Op(o) => {
-                    let node = find_op_grammar_atom(o);
-                    
-                    mut_position += 1;
-                    stack_expression.children.push(node);
-                }
-                _ => {
-                    let (node, next_position) = self.parse_expression(tokens, mut_position)?;
-                    stack_expression.children.push(node);
-                    mut_position = next_position;
-                }
-            };
+            }
+        }
+
+        let span = self.get_range_span(tokens, position, mut_position + 1);
+        let list = self.check_expression_depth(Expr::List { items, span }, depth)?;
+
+        Ok((list, mut_position + 1))
+    }
+
+    /// Parses a single value: a literal or a variable
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the expression to parse
+    /// * `position`            - Index position to parse
+    fn parse_expression(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "a value")?;
+        let span = c.span;
+
+        let expression = match &c.kind {
+            LexToken::Number(n) => Expr::Number { value: *n, span },
+            LexToken::Bool(b) => Expr::Bool { value: *b, span },
+            LexToken::Ident(v) => Expr::Variable { name: v.clone(), span },
+            LexToken::Str(v) => Expr::Text { value: v.clone(), span },
+            LexToken::Address(a) => Expr::Address { value: a.clone(), span },
+            _ => {
+                return Err(ParseError::UnexpectedToken {
+                    found: c.kind.to_string(),
+                    span,
+                }.into());
+            }
+        };
+
+        Ok((expression, position + 1))
+    }
+
+    /// Parses an assignment of the form `set name = expression ;`, or a
+    /// declaration of the form `let name = expression ;` or
+    /// `const name = expression ;`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the SET, LET or CONST keyword
+    fn parse_assignment(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Stmt, usize), ZqlError> {
+        let (name, next_position) = self.parse_name(tokens, position + 1, "a variable name")?;
+        let c = self.get_token(tokens, next_position, "'='")?;
+
+        match &c.kind {
+            LexToken::Op(o) if o == "=" => {}
+            _ => {
+                return Err(ParseError::Expected {
+                    expected: String::from("'='"),
+                    found: c.kind.to_string(),
+                    span: c.span,
+                }.into());
+            }
         }
 
-        println!("");
-        println!("STACK EXPRESSION: {:?}", stack_expression);
-        println!("");
+        let (value, next_position) = self.parse_binary_expression(tokens, next_position + 1, 0, 0)?;
+        self.expect_punctuation(tokens, next_position, ';', "';' to end the assignment")?;
+
+        let span = self.get_range_span(tokens, position, next_position + 1);
+
+        let statement = match tokens[position].kind {
+            LexToken::HeapKeyword(HeapKeyword::Let) => Stmt::Let { name, value, span },
+            LexToken::HeapKeyword(HeapKeyword::Const) => Stmt::Const { name, value, span },
+            _ => Stmt::Set { name, value, span },
+        };
+
+        Ok((statement, next_position + 1))
+    }
+
+    /// Parses a binary expression by precedence climbing. Operators binding
+    /// less tightly than `min_precedence` are left for the caller.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the expression to parse
+    /// * `position`            - Index position to parse
+    /// * `min_precedence`      - Lowest operator precedence to consume
+    /// * `depth`               - Levels of nesting the expression is found within
+    fn parse_binary_expression(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        min_precedence: u8,
+        depth: usize,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let (mut left_hand_side, mut mut_position) = self.parse_unary_expression(tokens, position, depth)?;
+
+        while let Some(Token { kind: LexToken::Op(o), span }) = tokens.get(mut_position) {
+            let op = find_op_atom(o, *span)?;
+
+            let precedence = match get_op_precedence(&op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+
+            // Parsing the right hand side one level higher keeps operators left associative
+            let (right_hand_side, next_position) =
+                self.parse_binary_expression(tokens, mut_position + 1, precedence + 1, depth + 1)?;
+
+            left_hand_side = self.check_expression_depth(Expr::Binary {
+                span: left_hand_side.span().join(right_hand_side.span()),
+                op,
+                left: Box::new(left_hand_side),
+                right: Box::new(right_hand_side),
+            }, depth)?;
+            mut_position = next_position;
+        }
+
+        Ok((left_hand_side, mut_position))
+    }
+
+    /// Parses a unary expression: a negated or `not` operand, a parenthesised
+    /// sub-expression, a list or a single value
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the expression to parse
+    /// * `position`            - Index position to parse
+    /// * `depth`               - Levels of nesting the expression is found within
+    fn parse_unary_expression(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        depth: usize,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "a value")?;
+        self.check_depth(depth, c.span)?;
+
+        match &c.kind {
+            LexToken::Op(o) if o == "-" || o == "not" => {
+                let (operand, next_position) = if o == "-" {
+                    self.parse_unary_expression(tokens, position + 1, depth + 1)?
+                } else {
+                    self.parse_binary_expression(tokens, position + 1, get_not_operand_precedence(), depth + 1)?
+                };
+
+                let expression = self.check_expression_depth(Expr::Unary {
+                    op: find_op_atom(o, c.span)?,
+                    span: c.span.join(operand.span()),
+                    operand: Box::new(operand),
+                }, depth)?;
+
+                Ok((expression, next_position))
+            }
+            LexToken::Punc('(') => {
+                let (inner, next_position) = self.parse_binary_expression(tokens, position + 1, 0, depth + 1)?;
+                self.expect_punctuation(tokens, next_position, ')', "')' to close the expression")?;
+
+                let span = c.span.join(tokens[next_position].span);
+
+                Ok((inner.with_span(span), next_position + 1))
+            }
+            LexToken::Punc('[') => self.parse_list(tokens, position, depth),
+            LexToken::Number(_)
+            | LexToken::Bool(_)
+            | LexToken::Ident(_)
+            | LexToken::Str(_)
+            | LexToken::Address(_) => {
+                self.parse_expression(tokens, position)
+            }
+            _ => Err(ParseError::Expected {
+                expected: String::from("a value"),
+                found: c.kind.to_string(),
+                span: c.span,
+            }.into()),
+        }
+    }
+
+    /// Parses a Stack statement of the form `stack [ ... ]`. Heap keywords
+    /// are not valid inside the brackets.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the STACK keyword
+    fn parse_stack_statement(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Stmt, usize), ZqlError> {
+        self.expect_punctuation(tokens, position + 1, '[', "'[' to open the Stack")?;
+
+        let c = self.get_token(tokens, position + 2, "a Stack keyword")?;
+
+        let (statement, next_position) = match &c.kind {
+            LexToken::StackKeyword(StackKeyword::Pay) => self.parse_payments(tokens, position + 2)?,
+            LexToken::Ident(_) => self.parse_stack_binding(tokens, position + 2)?,
+            LexToken::StackKeyword(StackKeyword::Create)
+            | LexToken::StackKeyword(StackKeyword::Update)
+            | LexToken::StackKeyword(StackKeyword::Delete) => self.parse_data_statement(tokens, position + 2)?,
+            LexToken::StackKeyword(StackKeyword::Transact) => self.parse_transaction(tokens, position + 2)?,
+            LexToken::StackKeyword(StackKeyword::New) => self.parse_mint(tokens, position + 2)?,
+            LexToken::StackKeyword(StackKeyword::Get) => {
+                return Err(SemanticError::InvalidStackStatement {
+                    expected: String::from("a variable to hold the result, as in 'name = GET ...'"),
+                    span: c.span,
+                }.into());
+            }
+            LexToken::StackKeyword(_k) => {
+                return Err(SemanticError::UnsupportedExpression {
+                    found: c.kind.to_string(),
+                    span: c.span,
+                }.into());
+            }
+            _ => {
+                return Err(SemanticError::InvalidStackStatement {
+                    expected: String::from("a Stack statement such as PAY"),
+                    span: c.span,
+                }.into());
+            }
+        };
+
+        self.expect_stack_end(tokens, next_position)?;
+        let span = self.get_range_span(tokens, position, next_position + 1);
+
+        Ok((Stmt::Stack { statement, span }, next_position + 1))
+    }
+
+    /// Parses a Stack statement storing its result in a Heap variable, of the
+    /// form `name = GET ...`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the variable name
+    fn parse_stack_binding(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(StackStmt, usize), ZqlError> {
+        let (target, next_position) = self.parse_name(tokens, position, "a variable name")?;
+        let c = self.get_token(tokens, next_position, "'='")?;
+
+        match &c.kind {
+            LexToken::Op(o) if o == "=" => {}
+            _ => {
+                return Err(ParseError::Expected {
+                    expected: String::from("'='"),
+                    found: c.kind.to_string(),
+                    span: c.span,
+                }.into());
+            }
+        }
+
+        let c = self.get_token(tokens, next_position + 1, "a Stack statement such as GET")?;
+
+        match &c.kind {
+            LexToken::StackKeyword(StackKeyword::Get) => {
+                let (query, next_position) = self.parse_query(tokens, next_position + 1)?;
+                let span = self.get_range_span(tokens, position, next_position);
+
+                Ok((StackStmt::Get { target, query, span }, next_position))
+            }
+            LexToken::StackKeyword(StackKeyword::New) => {
+                let c = self.get_token(tokens, next_position + 2, "ADDRESS")?;
+
+                if c.kind != LexToken::StackKeyword(StackKeyword::Address) {
+                    return Err(SemanticError::InvalidStackStatement {
+                        expected: String::from("ADDRESS, as only a new address can be assigned to a variable"),
+                        span: c.span,
+                    }.into());
+                }
+
+                let span = self.get_range_span(tokens, position, next_position + 3);
+
+                Ok((StackStmt::NewAddress { target, span }, next_position + 3))
+            }
+            _ => Err(SemanticError::InvalidStackStatement {
+                expected: String::from("a Stack statement with a result, such as GET or NEW ADDRESS"),
+                span: c.span,
+            }.into()),
+        }
+    }
+
+    /// Parses a query of the form `GET <field> [asset] WHERE <predicates>`, where
+    /// the predicates are joined by AND. Every query needs exactly one WHO
+    /// predicate to name the addresses it reads.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the GET keyword
+    fn parse_query(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Query, usize), ZqlError> {
+        let c = self.get_token(tokens, position + 1, "AMOUNT or TRANSACT")?;
+
+        let field = match &c.kind {
+            LexToken::StackKeyword(StackKeyword::Amount) => QueryField::Amount,
+            LexToken::StackKeyword(StackKeyword::Transact) => QueryField::Transact,
+            _ => {
+                return Err(SemanticError::InvalidStackStatement {
+                    expected: String::from("AMOUNT or TRANSACT to GET"),
+                    span: c.span,
+                }.into());
+            }
+        };
+
+        let mut mut_position = position + 2;
+        let mut asset = None;
+
+        if let Some(LexToken::StackKeyword(k)) = tokens.get(mut_position).map(|t| &t.kind) {
+            asset = Asset::from_keyword(k);
+
+            if asset.is_some() {
+                mut_position += 1;
+            }
+        }
+
+        let (predicates, next_position) = self.parse_where_clause(tokens, mut_position)?;
+        let span = self.get_range_span(tokens, position, next_position);
+        let who_count = predicates
+            .iter()
+            .filter(|p| !matches!(p, Predicate::Amount { .. }))
+            .count();
+
+        if who_count != 1 {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("exactly one WHO predicate"),
+                span,
+            }.into());
+        }
+
+        Ok((Query { field, asset, predicates, span }, next_position))
+    }
+
+    /// Parses a WHERE clause of one or more predicates joined by AND
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the WHERE keyword
+    fn parse_where_clause(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Vec<Predicate>, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "WHERE")?;
+
+        if c.kind != LexToken::StackKeyword(StackKeyword::Where) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("a WHERE clause"),
+                span: c.span,
+            }.into());
+        }
+
+        let mut predicates = Vec::new();
+        let mut mut_position = position;
+
+        loop {
+            let (predicate, next_position) = self.parse_predicate(tokens, mut_position + 1)?;
+            predicates.push(predicate);
+            mut_position = next_position;
+
+            match tokens.get(mut_position).map(|t| &t.kind) {
+                Some(LexToken::StackKeyword(StackKeyword::And)) => {}
+                _ => break,
+            }
+        }
+
+        Ok((predicates, mut_position))
+    }
+
+    /// Parses a data record statement: `CREATE <key> <content> ENCODING <encoding>`,
+    /// `UPDATE <key> <content> ENCODING <encoding> WHERE <predicates>` or
+    /// `DELETE <key> WHERE <predicates>`. UPDATE and DELETE change existing
+    /// records, so they must name the owner of the record with a WHERE clause.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the CREATE, UPDATE or DELETE keyword
+    fn parse_data_statement(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(StackStmt, usize), ZqlError> {
+        let keyword = &tokens[position].kind;
+        let (key, key_end) = self.parse_unary_expression(tokens, position + 1, 0)?;
+
+        if *keyword == LexToken::StackKeyword(StackKeyword::Delete) {
+            let (predicates, next_position) = self.parse_record_owners(tokens, position, key_end)?;
+            let span = self.get_range_span(tokens, position, next_position);
+
+            return Ok((StackStmt::Delete { key, predicates, span }, next_position));
+        }
+
+        let (content, content_end) = self.parse_unary_expression(tokens, key_end, 0)?;
+        let (encoding, encoding_end) = self.parse_encoding(tokens, content_end)?;
+
+        if *keyword == LexToken::StackKeyword(StackKeyword::Update) {
+            let (predicates, next_position) = self.parse_record_owners(tokens, position, encoding_end)?;
+            let span = self.get_range_span(tokens, position, next_position);
+
+            return Ok((StackStmt::Update { key, content, encoding, predicates, span }, next_position));
+        }
+
+        if let Some(c) = tokens.get(encoding_end) {
+            if c.kind == LexToken::StackKeyword(StackKeyword::Where) {
+                return Err(SemanticError::InvalidStackStatement {
+                    expected: String::from("no WHERE clause, as CREATE makes a new record"),
+                    span: c.span,
+                }.into());
+            }
+        }
+
+        let span = self.get_range_span(tokens, position, encoding_end);
+
+        Ok((StackStmt::Create { key, content, encoding, span }, encoding_end))
+    }
+
+    /// Parses the required WHERE clause of an UPDATE or DELETE statement, which
+    /// may only name the owner of the record with WHO predicates
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `keyword_position`    - Index position of the UPDATE or DELETE keyword
+    /// * `position`            - Index position the WHERE keyword is expected at
+    fn parse_record_owners(
+        &self,
+        tokens: &[Token],
+        keyword_position: usize,
+        position: usize,
+    ) -> Result<(Vec<Predicate>, usize), ZqlError> {
+        if tokens.get(position).map(|t| &t.kind) != Some(&LexToken::StackKeyword(StackKeyword::Where)) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: format!(
+                    "a WHERE clause naming the owner of the record to {}",
+                    tokens[keyword_position].kind
+                ),
+                span: self.get_range_span(tokens, keyword_position, position),
+            }.into());
+        }
+
+        let (predicates, next_position) = self.parse_where_clause(tokens, position)?;
+
+        if let Some(predicate) = predicates.iter().find(|p| matches!(p, Predicate::Amount { .. })) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("only WHO predicates on the owner of a record"),
+                span: predicate.span(),
+            }.into());
+        }
+
+        Ok((predicates, next_position))
+    }
+
+    /// Parses an encoding clause of the form `ENCODING <encoding>`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the ENCODING keyword
+    fn parse_encoding(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(DataEncoding, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "ENCODING")?;
+
+        if c.kind != LexToken::StackKeyword(StackKeyword::Encoding) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("an ENCODING clause"),
+                span: c.span,
+            }.into());
+        }
+
+        let c = self.get_token(tokens, position + 1, "an encoding such as json, utf8 or hex")?;
+
+        let encoding = match &c.kind {
+            LexToken::Ident(name) => DataEncoding::from_name(name),
+            _ => None,
+        }.ok_or_else(|| SemanticError::InvalidStackStatement {
+            expected: String::from("an encoding such as json, utf8 or hex"),
+            span: c.span,
+        })?;
+
+        Ok((encoding, position + 2))
+    }
+
+    /// Parses a single predicate of a WHERE clause: `WHO = <address>`,
+    /// `WHO IN <list>` or `AMOUNT <comparison> <value>`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of WHO or AMOUNT
+    fn parse_predicate(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Predicate, usize), ZqlError> {
+        let c = self.get_token(tokens, position, "WHO or AMOUNT")?;
+
+        if !matches!(c.kind, LexToken::StackKeyword(StackKeyword::Who) | LexToken::StackKeyword(StackKeyword::Amount)) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("a WHO or AMOUNT predicate"),
+                span: c.span,
+            }.into());
+        }
+
+        let operator = self.get_token(tokens, position + 1, "a comparison")?;
+
+        let op = match &operator.kind {
+            // A single '=' compares in a WHERE clause, just as in SQL
+            LexToken::Op(o) if o == "=" => Some(OpAtom::EqualTo),
+            LexToken::Op(o) => find_op_atom(o, operator.span).ok(),
+            LexToken::StackKeyword(StackKeyword::In) => None,
+            _ => {
+                return Err(ParseError::Expected {
+                    expected: String::from("a comparison"),
+                    found: operator.kind.to_string(),
+                    span: operator.span,
+                }.into());
+            }
+        };
+
+        match (&c.kind, op) {
+            (LexToken::StackKeyword(StackKeyword::Who), Some(OpAtom::EqualTo)) => {
+                let (address, next_position) = self.parse_unary_expression(tokens, position + 2, 0)?;
+                let span = self.get_range_span(tokens, position, next_position);
+
+                Ok((Predicate::WhoIs { address, span }, next_position))
+            }
+            (LexToken::StackKeyword(StackKeyword::Who), None) => {
+                let (addresses, next_position) = self.parse_unary_expression(tokens, position + 2, 0)?;
+                let span = self.get_range_span(tokens, position, next_position);
+
+                Ok((Predicate::WhoIn { addresses, span }, next_position))
+            }
+            (
+                LexToken::StackKeyword(StackKeyword::Amount),
+                Some(op @ OpAtom::EqualTo)
+                | Some(op @ OpAtom::NotEqualTo)
+                | Some(op @ OpAtom::LessThan)
+                | Some(op @ OpAtom::GreaterThan)
+                | Some(op @ OpAtom::LessThanOrEqualTo)
+                | Some(op @ OpAtom::GreaterThanOrEqualTo),
+            ) => {
+                let (value, next_position) = self.parse_binary_expression(tokens, position + 2, 0, 0)?;
+                let span = self.get_range_span(tokens, position, next_position);
+
+                Ok((Predicate::Amount { op, value, span }, next_position))
+            }
+            (LexToken::StackKeyword(StackKeyword::Who), _) => Err(SemanticError::InvalidStackStatement {
+                expected: String::from("'=' or IN after WHO"),
+                span: operator.span,
+            }.into()),
+            _ => Err(SemanticError::InvalidStackStatement {
+                expected: String::from("a comparison after AMOUNT"),
+                span: operator.span,
+            }.into()),
+        }
+    }
+
+    /// Parses a transaction of the form `TRANSACT [ <statement>; <statement> ]`,
+    /// grouping PAY, NEW, CREATE, UPDATE and DELETE statements so that they succeed
+    /// or fail together
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the TRANSACT keyword
+    fn parse_transaction(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(StackStmt, usize), ZqlError> {
+        self.expect_punctuation(tokens, position + 1, '[', "'[' to open the TRANSACT block")?;
+
+        let mut statements = Vec::new();
+        let mut mut_position = position + 2;
+
+        loop {
+            let c = self.get_token(tokens, mut_position, "a PAY, NEW, CREATE, UPDATE or DELETE statement")?;
+
+            let (statement, next_position) = match &c.kind {
+                LexToken::StackKeyword(StackKeyword::Pay) => self.parse_payments(tokens, mut_position)?,
+                LexToken::StackKeyword(StackKeyword::New) => self.parse_mint(tokens, mut_position)?,
+                LexToken::StackKeyword(StackKeyword::Create)
+                | LexToken::StackKeyword(StackKeyword::Update)
+                | LexToken::StackKeyword(StackKeyword::Delete) => self.parse_data_statement(tokens, mut_position)?,
+                _ => {
+                    return Err(SemanticError::InvalidStackStatement {
+                        expected: String::from("a PAY, NEW, CREATE, UPDATE or DELETE statement in the TRANSACT block"),
+                        span: c.span,
+                    }.into());
+                }
+            };
+
+            statements.push(statement);
+
+            let c = self.get_token(tokens, next_position, "']' to close the TRANSACT block")?;
+
+            match &c.kind {
+                LexToken::Punc(';') => mut_position = next_position + 1,
+                LexToken::Punc(']') => {
+                    mut_position = next_position;
+                    break;
+                }
+                _ => {
+                    return Err(ParseError::Expected {
+                        expected: String::from("';' or ']' to close the TRANSACT block"),
+                        found: c.kind.to_string(),
+                        span: c.span,
+                    }.into());
+                }
+            }
+        }
+
+        let span = self.get_range_span(tokens, position, mut_position + 1);
+
+        Ok((StackStmt::Transact { statements, span }, mut_position + 1))
+    }
+
+    /// Checks that a Stack statement is closed by `]`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the closing `]`
+    fn expect_stack_end(&self, tokens: &[Token], position: usize) -> Result<(), ParseError> {
+        let c = self.get_token(tokens, position, "']' to close the Stack")?;
+
+        match &c.kind {
+            LexToken::Punc(']') => Ok(()),
+            LexToken::HeapKeyword(_k) => Err(ParseError::HeapKeywordInStack {
+                keyword: c.kind.to_string(),
+                span: c.span,
+            }),
+            _ => Err(ParseError::Expected {
+                expected: String::from("']' to close the Stack"),
+                found: c.kind.to_string(),
+                span: c.span,
+            }),
+        }
+    }
+
+    /// Parses one or more payments chained with AND, such as
+    /// `PAY <address> <amount> <asset> AND PAY <address> <amount> <asset>`
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the first PAY keyword
+    fn parse_payments(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(StackStmt, usize), ZqlError> {
+        let mut payments = Vec::new();
+        let mut mut_position = position;
+
+        loop {
+            let (payment, next_position) = self.parse_payment(tokens, mut_position)?;
+            payments.push(payment);
+            mut_position = next_position;
+
+            match tokens.get(mut_position).map(|t| &t.kind) {
+                Some(LexToken::StackKeyword(StackKeyword::And)) => {}
+                _ => break,
+            }
+
+            let c = self.get_token(tokens, mut_position + 1, "PAY after AND")?;
+
+            if c.kind != LexToken::StackKeyword(StackKeyword::Pay) {
+                return Err(SemanticError::InvalidStackStatement {
+                    expected: String::from("PAY after AND"),
+                    span: c.span,
+                }.into());
+            }
+
+            mut_position += 1;
+        }
+
+        let span = self.get_range_span(tokens, position, mut_position);
+
+        Ok((StackStmt::Pay { payments, span }, mut_position))
+    }
+
+    /// Parses a payment of the form `PAY <address> <amount> <asset>`. The amount
+    /// may be any expression.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the PAY keyword
+    fn parse_payment(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(Payment, usize), ZqlError> {
+        let (payee, next_position) = self.parse_payee(tokens, position + 1, "an address to pay")?;
+        let (amount, next_position) = self.parse_amount(tokens, next_position, "a numeric amount to pay")?;
+        let asset = self.parse_asset(tokens, next_position)?;
+        let span = self.get_range_span(tokens, position, next_position + 1);
+
+        Ok((Payment { payee, amount, asset, span }, next_position + 1))
+    }
+
+    /// Parses a mint of the form `NEW <asset> <amount> TO <address>`, which
+    /// issues new units of the asset to the address
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the NEW keyword
+    fn parse_mint(
+        &self,
+        tokens: &[Token],
+        position: usize,
+    ) -> Result<(StackStmt, usize), ZqlError> {
+        let c = self.get_token(tokens, position + 1, "an asset such as ZNT or SDL")?;
+
+        if c.kind == LexToken::StackKeyword(StackKeyword::Address) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("a variable to hold the address, as in 'name = NEW ADDRESS'"),
+                span: c.span,
+            }.into());
+        }
+
+        let asset = self.parse_asset(tokens, position + 1)?;
+        let (amount, next_position) = self.parse_amount(tokens, position + 2, "a numeric amount to mint")?;
+        let c = self.get_token(tokens, next_position, "TO")?;
+
+        if c.kind != LexToken::StackKeyword(StackKeyword::To) {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from("TO followed by the address to mint to"),
+                span: c.span,
+            }.into());
+        }
+
+        let (payee, next_position) = self.parse_payee(tokens, next_position + 1, "an address to mint to")?;
+        let span = self.get_range_span(tokens, position, next_position);
+
+        Ok((StackStmt::Mint { payee, amount, asset, span }, next_position))
+    }
+
+    /// Parses the address receiving a payment or mint. The address is a single
+    /// value so that a negative amount can't be mistaken for a subtraction.
+    /// Literals that can never be an address are rejected here, while variables
+    /// are checked once their values are known.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the address
+    /// * `expected`            - Description of the address expected
+    fn parse_payee(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        expected: &str,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let (payee, next_position) = self.parse_unary_expression(tokens, position, 0)?;
+
+        if let Expr::Number { span, .. }
+        | Expr::Bool { span, .. }
+        | Expr::Text { span, .. }
+        | Expr::List { span, .. } = payee
+        {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from(expected),
+                span,
+            }.into());
+        }
+
+        Ok((payee, next_position))
+    }
+
+    /// Parses the amount of a payment or mint. Literals that can never be an
+    /// amount are rejected here, while variables are checked once their values
+    /// are known.
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position the amount starts at
+    /// * `expected`            - Description of the amount expected
+    fn parse_amount(
+        &self,
+        tokens: &[Token],
+        position: usize,
+        expected: &str,
+    ) -> Result<(Expr, usize), ZqlError> {
+        let (amount, next_position) = self.parse_binary_expression(tokens, position, 0, 0)?;
+
+        if let Expr::Bool { span, .. }
+        | Expr::Text { span, .. }
+        | Expr::Address { span, .. }
+        | Expr::List { span, .. } = amount
+        {
+            return Err(SemanticError::InvalidStackStatement {
+                expected: String::from(expected),
+                span,
+            }.into());
+        }
+
+        Ok((amount, next_position))
+    }
+
+    /// Parses an asset keyword such as ZNT or SDL
+    ///
+    /// ### Arguments
+    ///
+    /// * `tokens`              - Tokens of the script
+    /// * `position`            - Index position of the asset keyword
+    fn parse_asset(&self, tokens: &[Token], position: usize) -> Result<Asset, ZqlError> {
+        let c = self.get_token(tokens, position, "an asset such as ZNT or SDL")?;
+
+        let asset = match &c.kind {
+            LexToken::StackKeyword(k) => Asset::from_keyword(k),
+            _ => None,
+        }.ok_or_else(|| SemanticError::InvalidStackStatement {
+            expected: String::from("an asset such as ZNT or SDL"),
+            span: c.span,
+        })?;
+
+        Ok(asset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{ParseError, SemanticError, ZqlError};
+    use crate::grammar::MAX_NESTING_DEPTH;
+    use super::{Expr, Parser, Stmt};
+
+    /// Parses `let x = <expression>;` and gets the expression assigned
+    fn parse_value(expression: &str) -> Result<Expr, ZqlError> {
+        let script = Parser.parse_script(&format!("let x = {};", expression))?;
+
+        match script.statements.into_iter().next() {
+            Some(Stmt::Let { value, .. }) => Ok(value),
+            other => panic!("expected an assignment, found {:?}", other),
+        }
+    }
+
+    /// Writes an expression out with every operator and its operands in
+    /// parentheses, so the shape of the tree can be compared directly
+    fn grouped(expression: &Expr) -> String {
+        match expression {
+            Expr::Number { value, .. } => value.to_string(),
+            Expr::Bool { value, .. } => value.to_string(),
+            Expr::Unary { op, operand, .. } => format!("({:?} {})", op, grouped(operand)),
+            Expr::Binary { op, left, right, .. } => format!("({:?} {} {})", op, grouped(left), grouped(right)),
+            other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    fn assert_grouping(expression: &str, expected: &str) {
+        assert_eq!(grouped(&parse_value(expression).unwrap()), expected);
+    }
+
+    fn assert_too_deep(result: Result<impl std::fmt::Debug, ZqlError>) {
+        match result {
+            Err(ZqlError::Parse(ParseError::NestingTooDeep { limit, .. })) => assert_eq!(limit, MAX_NESTING_DEPTH),
+            other => panic!("expected the nesting to be too deep, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_before_addition() {
+        assert_grouping("1 + 2 * 3", "(Add 1 (Multiply 2 3))");
+        assert_grouping("1 * 2 + 3", "(Add (Multiply 1 2) 3)");
+        assert_grouping("8 / 4 - 1", "(Subtract (Divide 8 4) 1)");
+    }
+
+    #[test]
+    fn operators_of_equal_precedence_associate_left() {
+        assert_grouping("10 - 4 - 3", "(Subtract (Subtract 10 4) 3)");
+        assert_grouping("12 / 3 * 2", "(Multiply (Divide 12 3) 2)");
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_grouping("(1 + 2) * 3", "(Multiply (Add 1 2) 3)");
+        assert_grouping("10 - (4 - 3)", "(Subtract 10 (Subtract 4 3))");
+    }
+
+    #[test]
+    fn negation_binds_tightest() {
+        assert_grouping("-2 * 3", "(Multiply (Subtract 2) 3)");
+        assert_grouping("2 - -3", "(Subtract 2 (Subtract 3))");
+    }
+
+    #[test]
+    fn comparisons_bind_between_arithmetic_and_logic() {
+        assert_grouping("1 + 2 < 4", "(LessThan (Add 1 2) 4)");
+        assert_grouping("1 < 2 == true", "(EqualTo (LessThan 1 2) true)");
+        assert_grouping("true or false and false", "(Or true (And false false))");
+        assert_grouping("not 1 == 2 and true", "(And (Not (EqualTo 1 2)) true)");
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses() {
+        let depth = MAX_NESTING_DEPTH - 1;
+        let parenthesised = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let chained = vec!["1"; MAX_NESTING_DEPTH].join(" + ");
+
+        assert_eq!(parse_value(&parenthesised).unwrap().depth(), 1);
+        assert_eq!(parse_value(&chained).unwrap().depth(), MAX_NESTING_DEPTH);
+    }
+
+    #[test]
+    fn deeply_nested_parentheses_are_rejected() {
+        assert_too_deep(parse_value(&format!("{}1{}", "(".repeat(1_000), ")".repeat(1_000))));
+    }
+
+    #[test]
+    fn long_operator_chains_are_rejected() {
+        assert_too_deep(parse_value(&vec!["1"; 20_000].join(" + ")));
+        assert_too_deep(parse_value(&format!("{}1", "- ".repeat(1_000))));
+        assert_too_deep(parse_value(&format!("1{}", " + (1".repeat(1_000) + &")".repeat(1_000))));
+    }
+
+    #[test]
+    fn deeply_nested_lists_are_rejected() {
+        assert_too_deep(parse_value(&format!("{}1{}", "[".repeat(1_000), "]".repeat(1_000))));
+    }
+
+    #[test]
+    fn deeply_nested_blocks_are_rejected() {
+        let nested_ifs = format!("{}{}", "if true { ".repeat(1_000), "}".repeat(1_000));
+        let else_ifs = format!("if false {{ }}{}", " else if false { }".repeat(1_000));
+
+        assert_too_deep(Parser.parse_script(&nested_ifs));
+        assert_too_deep(Parser.parse_script(&else_ifs));
+    }
+
+    #[test]
+    fn assignment_requires_a_declaration() {
+        let result = Parser.parse_script("set x = 1;");
+
+        assert!(matches!(result, Err(ZqlError::Semantic(SemanticError::UndeclaredVariable { .. }))));
+        assert!(Parser.parse_script("let x = 1; set x = 2;").is_ok());
+    }
+
+    #[test]
+    fn variables_cannot_be_declared_twice() {
+        let redeclared = Parser.parse_script("let x = 1; let x = 2;");
+        let shadowed = Parser.parse_script("const x = 1; if true { let x = 2; }");
+
+        assert!(matches!(redeclared, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+        assert!(matches!(shadowed, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+    }
+
+    #[test]
+    fn loop_variables_cannot_shadow_declarations() {
+        let shadowed = Parser.parse_script("const x = 1; for x in [2] { set x = 3; }");
+        let nested = Parser.parse_script("for x in [1] { for x in [2] { } }");
+
+        assert!(matches!(shadowed, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+        assert!(matches!(nested, Err(ZqlError::Semantic(SemanticError::AlreadyDeclared { .. }))));
+        assert!(Parser.parse_script("for x in [1] { } for x in [2] { set x = 3; }").is_ok());
+    }
+
+    #[test]
+    fn constants_cannot_be_reassigned() {
+        let result = Parser.parse_script("const x = 1; set x = 2;");
+        let from_global = Parser.parse_script_with_globals("set x = 2;", &[("x".to_string(), true)]);
 
-        Ok((stack_expression, mut_position))
+        assert!(matches!(result, Err(ZqlError::Semantic(SemanticError::ConstantReassignment { .. }))));
+        assert!(matches!(from_global, Err(ZqlError::Semantic(SemanticError::ConstantReassignment { .. }))));
+        assert!(Parser.parse_script_with_globals("set x = 2;", &[("x".to_string(), false)]).is_ok());
     }
 }
@@ -0,0 +1,159 @@
+//! The transactions a script asks the host to perform. The compiler only ever
+//! produces these as intents, which the kernel then applies to its ledger.
+
+use std::fmt;
+use crate::address::Address;
+use crate::decimal::Decimal;
+use crate::grammar::StackKeyword;
+use crate::lexer::Span;
+
+/// Assets that can be transferred by a Stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Asset {
+    Znt,
+    Sdl
+}
+
+/// A single transfer of an asset to an address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub payee: Address,
+    pub amount: Decimal,
+    pub asset: Asset
+}
+
+/// How the content of a data record is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DataEncoding {
+    Json,
+    Utf8,
+    Hex
+}
+
+/// A change to a data record on the ledger
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataOperation {
+    /// Creates a new record owned by the payer
+    Create { key: String, content: String, encoding: DataEncoding },
+    /// Replaces the content of a record, provided one of the owners holds it
+    Update { key: String, content: String, encoding: DataEncoding, owners: Vec<Address> },
+    /// Removes a record, provided one of the owners holds it
+    Delete { key: String, owners: Vec<Address> }
+}
+
+/// A single change a transaction makes to the ledger
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionOperation {
+    /// Pays an amount of an asset from the payer to the payee
+    Transfer(Transfer),
+    /// Issues new units of an asset to the payee, rather than paying them
+    Mint(Transfer),
+    Data(DataOperation),
+    /// Records an address created with NEW ADDRESS, so it is never created again
+    CreateAddress(Address)
+}
+
+/// The operations produced by a single Stack statement on behalf of the payer,
+/// in the order the script gave them. The host must perform them atomically
+/// and in order, so either all of them happen or none do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionIntent {
+    /// Location of the Stack statement that produced the transaction
+    pub span: Span,
+    pub payer: Address,
+    pub operations: Vec<TransactionOperation>
+}
+
+
+/*------ IMPLEMENTATIONS ------*/
+
+impl Asset {
+    /// Get the asset for the provided stack keyword, if the keyword names one
+    ///
+    /// ### Arguments
+    ///
+    /// * `keyword` - The stack keyword to convert
+    pub fn from_keyword(keyword: &StackKeyword) -> Option<Asset> {
+        match keyword {
+            StackKeyword::Znt => { Some(Asset::Znt) }
+            StackKeyword::Sdl => { Some(Asset::Sdl) }
+            _ =>                 { None }
+        }
+    }
+
+    /// Get the number of decimal places the asset can be divided into.
+    /// Amounts of the asset are rounded to this precision before transfer.
+    pub fn precision(&self) -> u32 {
+        match self {
+            Asset::Znt => { 6 }
+            Asset::Sdl => { 0 }
+        }
+    }
+}
+
+impl TransactionIntent {
+    /// Creates a transaction for the payer that performs the given operations
+    ///
+    /// ### Arguments
+    ///
+    /// * `payer`       - The address the transaction is on behalf of
+    /// * `span`        - Location of the Stack statement producing the transaction
+    /// * `operations`  - Operations of the transaction, in the order to perform them
+    pub fn new(payer: Address, span: Span, operations: Vec<TransactionOperation>) -> TransactionIntent {
+        TransactionIntent {
+            span,
+            payer,
+            operations
+        }
+    }
+}
+
+impl DataEncoding {
+    /// Get the encoding for the name used in an ENCODING clause
+    ///
+    /// ### Arguments
+    ///
+    /// * `name`    - The name of the encoding
+    pub fn from_name(name: &str) -> Option<DataEncoding> {
+        match name {
+            "json" => { Some(DataEncoding::Json) }
+            "utf8" => { Some(DataEncoding::Utf8) }
+            "hex" =>  { Some(DataEncoding::Hex) }
+            _ =>      { None }
+        }
+    }
+
+    /// Checks whether content can be stored with this encoding. Script strings
+    /// are always valid UTF-8, hex content needs an even number of hex digits
+    /// and JSON content must be a single well-formed JSON value.
+    ///
+    /// ### Arguments
+    ///
+    /// * `content` - The content to check
+    pub fn is_valid(&self, content: &str) -> bool {
+        match self {
+            DataEncoding::Json => serde_json::from_str::<serde_json::Value>(content).is_ok(),
+            DataEncoding::Hex => content.len() % 2 == 0 && content.chars().all(|c| c.is_ascii_hexdigit()),
+            DataEncoding::Utf8 => true
+        }
+    }
+}
+
+impl fmt::Display for DataEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataEncoding::Json => write!(f, "json"),
+            DataEncoding::Utf8 => write!(f, "utf8"),
+            DataEncoding::Hex => write!(f, "hex")
+        }
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asset::Znt => write!(f, "ZNT"),
+            Asset::Sdl => write!(f, "SDL")
+        }
+    }
+}